@@ -0,0 +1,383 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{rename, File};
+use std::io;
+use std::io::prelude::*;
+
+const ALIAS_SEPARATOR : &str = " = ";
+const IMPLICATION_SEPARATOR : &str = " => ";
+const COMMENT_PREFIX : &str = "#";
+
+/// Configuration of the tags, persisted in a file next to the root of the
+/// indexed tree, one `alias = canonical` or `antecedent => consequent` per line.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    path : String,
+    aliases : HashMap<String, String>,
+    /// antecedent to its direct consequents, an entry tagged with the
+    /// antecedent matches its consequents in queries
+    implications : HashMap<String, HashSet<String>>,
+    /// the tags of a directory apply to all its descendants, not saved
+    inherit : bool,
+    /// lines of the file, written back in place by `save`
+    lines : Vec<Line>
+}
+
+/// Line of the configuration file, a rule being kept as stored.
+#[derive(Debug, Clone, PartialEq)]
+enum Line {
+    /// comment, blank or ignored line
+    Verbatim(String),
+    Alias(String, String),
+    Implication(String, String)
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Line::Verbatim(ref line) => write!(f, "{}", line),
+            Line::Alias(ref alias, ref canonical) => write!(f, "{}{}{}", alias, ALIAS_SEPARATOR, canonical),
+            Line::Implication(ref antecedent, ref consequent) =>
+                write!(f, "{}{}{}", antecedent, IMPLICATION_SEPARATOR, consequent)
+        }
+    }
+}
+
+/// Path of the configuration file of a root, `/data/.finance.tag_engine` for
+/// `/data/finance`.
+pub fn config_path(base_path : &str, root_name : &str) -> String {
+    format!("{}.{}.tag_engine", base_path, root_name)
+}
+
+impl Config {
+    /// Read the configuration file, a missing file being an empty configuration.
+    pub fn load(path : String) -> io::Result<Self> {
+        let mut config = Self { path, aliases : HashMap::new(), implications : HashMap::new(), inherit : false,
+            lines : Vec::new() };
+        let mut content = String::new();
+        match File::open(&config.path) {
+            Ok(mut file) => { file.read_to_string(&mut content)?; },
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => return Ok(config),
+            Err(error) => return Err(error)
+        }
+        config.parse(&content);
+        Ok(config)
+    }
+
+    fn parse(&mut self, content : &str) {
+        for raw_line in content.lines() {
+            let line = raw_line.trim();
+            let parsed = if line.is_empty() || line.starts_with(COMMENT_PREFIX) {
+                None
+            }
+            else if let Some(index) = line.find(IMPLICATION_SEPARATOR) {
+                let antecedent = line[..index].trim();
+                let consequent = line[index + IMPLICATION_SEPARATOR.len()..].trim();
+                match self.insert_implication(antecedent, consequent) {
+                    Ok(()) => Some(Line::Implication(self.canonical(antecedent).to_string(),
+                        self.canonical(consequent).to_string())),
+                    Err(error) => {
+                        eprintln!("{} : ignore implication {:?}, {}", self.path, line, error);
+                        None
+                    }
+                }
+            }
+            else {
+                match line.find(ALIAS_SEPARATOR) {
+                    Some(index) => {
+                        let alias = line[..index].trim();
+                        let canonical = line[index + ALIAS_SEPARATOR.len()..].trim();
+                        match self.insert_alias(alias, canonical) {
+                            Ok(()) => Some(Line::Alias(alias.to_string(), self.canonical(alias).to_string())),
+                            Err(error) => {
+                                eprintln!("{} : ignore alias {:?}, {}", self.path, line, error);
+                                None
+                            }
+                        }
+                    },
+                    None => {
+                        eprintln!("{} : ignore line {:?}", self.path, line);
+                        None
+                    }
+                }
+            };
+            self.lines.push(parsed.unwrap_or_else(|| Line::Verbatim(raw_line.to_string())));
+        }
+    }
+
+    /// Write the configuration through a temporary file, so that an interrupted
+    /// write leaves the previous one intact. The lines of the file are kept in
+    /// place, but for the removed rules, and the new rules are appended.
+    pub fn save(&self) -> io::Result<()> {
+        let mut content = String::new();
+        for line in &self.lines {
+            let kept = match *line {
+                Line::Verbatim(_) => true,
+                Line::Alias(ref alias, ref canonical) => self.aliases.get(alias) == Some(canonical),
+                Line::Implication(ref antecedent, ref consequent) => self.implications.get(antecedent)
+                    .is_some_and(|consequents| consequents.contains(consequent))
+            };
+            if kept {
+                content.push_str(&format!("{}\n", line));
+            }
+        }
+        let mut aliases : Vec<Line> = self.aliases.iter()
+            .map(|(alias, canonical)| Line::Alias(alias.clone(), canonical.clone())).collect();
+        aliases.sort_by_key(|line| line.to_string());
+        let mut implications : Vec<Line> = self.implications.iter()
+            .flat_map(|(antecedent, consequents)| consequents.iter()
+                .map(move |consequent| Line::Implication(antecedent.clone(), consequent.clone())))
+            .collect();
+        implications.sort_by_key(|line| line.to_string());
+        for line in aliases.into_iter().chain(implications) {
+            if !self.lines.contains(&line) {
+                content.push_str(&format!("{}\n", line));
+            }
+        }
+        let temporary = format!("{}.tmp", self.path);
+        {
+            let mut file = File::create(&temporary)?;
+            file.write_all(content.as_bytes())?;
+        }
+        rename(temporary, &self.path)
+    }
+
+    fn insert_alias(&mut self, alias : &str, canonical : &str) -> Result<(), String> {
+        let canonical = self.canonical(canonical).to_string();
+        if alias.is_empty() || canonical.is_empty() {
+            Err(String::from("empty tag"))
+        }
+        else if alias == canonical {
+            Err(format!("{:?} is already the canonical tag", alias))
+        }
+        else if self.aliases.values().any(|tag| tag == alias) {
+            Err(format!("{:?} is the canonical tag of other aliases", alias))
+        }
+        else {
+            self.aliases.insert(alias.to_string(), canonical.clone());
+            // the rules written with the alias now apply to the canonical tag
+            if let Some((antecedent, consequent)) = self.cycle() {
+                self.aliases.remove(alias);
+                return Err(format!("the rule {:?} => {:?} would make a cycle", antecedent, consequent));
+            }
+            Ok(())
+        }
+    }
+
+    /// A rule whose consequent implies its antecedent, through the aliases.
+    fn cycle(&self) -> Option<(String, String)> {
+        self.implications.iter()
+            .flat_map(|(antecedent, consequents)| consequents.iter().map(move |consequent| (antecedent, consequent)))
+            .find(|&(antecedent, consequent)| self.implies(consequent, antecedent))
+            .map(|(antecedent, consequent)| (antecedent.clone(), consequent.clone()))
+    }
+
+    /// Make `alias` resolve to `canonical`, or to its canonical tag if
+    /// `canonical` is an alias itself, and save the configuration.
+    pub fn add_alias(&mut self, alias : &str, canonical : &str) -> Result<(), String> {
+        self.insert_alias(alias, canonical)?;
+        self.save().map_err(|error| error.to_string())
+    }
+
+    /// Remove an alias and save the configuration, `false` if it wasn't one.
+    pub fn remove_alias(&mut self, alias : &str) -> Result<bool, String> {
+        match self.aliases.remove(alias) {
+            Some(_) => self.save().map(|_| true).map_err(|error| error.to_string()),
+            None => Ok(false)
+        }
+    }
+
+    /// Check if `antecedent` implies `consequent`, directly or through other rules.
+    pub fn implies(&self, antecedent : &str, consequent : &str) -> bool {
+        let consequent = self.canonical(consequent);
+        let mut visited = HashSet::new();
+        let mut stack = vec![self.canonical(antecedent)];
+        while let Some(tag) = stack.pop() {
+            if tag == consequent {
+                return true;
+            }
+            if visited.insert(tag) {
+                // rules are kept as written, their antecedent may be an alias added afterwards
+                for (next_antecedent, consequents) in &self.implications {
+                    if self.canonical(next_antecedent) == tag {
+                        stack.extend(consequents.iter().map(|next| self.canonical(next)));
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    fn insert_implication(&mut self, antecedent : &str, consequent : &str) -> Result<(), String> {
+        let antecedent = self.canonical(antecedent).to_string();
+        let consequent = self.canonical(consequent).to_string();
+        if antecedent.is_empty() || consequent.is_empty() {
+            Err(String::from("empty tag"))
+        }
+        else if self.implies(&consequent, &antecedent) {
+            Err(format!("{:?} already implies {:?}, this rule would make a cycle", consequent, antecedent))
+        }
+        else {
+            self.implications.entry(antecedent).or_default().insert(consequent);
+            Ok(())
+        }
+    }
+
+    /// Add the rule `antecedent => consequent` between canonical tags, unless
+    /// it makes a cycle, and save the configuration.
+    pub fn add_implication(&mut self, antecedent : &str, consequent : &str) -> Result<(), String> {
+        self.insert_implication(antecedent, consequent)?;
+        self.save().map_err(|error| error.to_string())
+    }
+
+    /// Remove a rule and save the configuration, `false` if it wasn't one.
+    pub fn remove_implication(&mut self, antecedent : &str, consequent : &str) -> Result<bool, String> {
+        let antecedent = self.canonical(antecedent).to_string();
+        let consequent = self.canonical(consequent).to_string();
+        let mut removed = false;
+        for (next_antecedent, consequents) in self.implications.iter_mut() {
+            if self.aliases.get(next_antecedent).unwrap_or(next_antecedent) == &antecedent {
+                let aliases = &self.aliases;
+                let count = consequents.len();
+                consequents.retain(|next| aliases.get(next).unwrap_or(next) != &consequent);
+                removed |= consequents.len() != count;
+            }
+        }
+        if !removed {
+            return Ok(false);
+        }
+        self.implications.retain(|_, consequents| !consequents.is_empty());
+        self.save().map(|_| true).map_err(|error| error.to_string())
+    }
+
+    pub fn inherit(&self) -> bool {
+        self.inherit
+    }
+
+    pub fn set_inherit(&mut self, inherit : bool) {
+        self.inherit = inherit;
+    }
+
+    pub fn canonical<'a>(&'a self, tag : &'a str) -> &'a str {
+        match self.aliases.get(tag) {
+            Some(canonical) => canonical,
+            None => tag
+        }
+    }
+
+    /// The canonical tag of `tag` and all its aliases.
+    pub fn synonyms<'a>(&'a self, tag : &'a str) -> Vec<&'a str> {
+        let canonical = self.canonical(tag);
+        let mut synonyms = vec![canonical];
+        for (alias, target) in &self.aliases {
+            if target == canonical {
+                synonyms.push(alias);
+            }
+        }
+        synonyms
+    }
+
+    /// The tags named by the aliases and the rules, entries may have none of them.
+    pub fn tags(&self) -> HashSet<&str> {
+        let aliases = self.aliases.iter().flat_map(|(alias, canonical)| vec![alias, canonical]);
+        let rules = self.implications.iter()
+            .flat_map(|(antecedent, consequents)| Some(antecedent).into_iter().chain(consequents));
+        aliases.chain(rules).map(|tag| tag.as_str()).collect()
+    }
+
+    /// The tags whose entries match `tag` in a query : its synonyms and the
+    /// synonyms of all the tags implying it.
+    pub fn matching_tags<'a>(&'a self, tag : &'a str) -> Vec<&'a str> {
+        let mut tags = self.synonyms(tag);
+        let mut visited = HashSet::new();
+        let mut stack = vec![self.canonical(tag)];
+        while let Some(consequent) = stack.pop() {
+            if !visited.insert(consequent) {
+                continue;
+            }
+            for (antecedent, consequents) in &self.implications {
+                if consequents.iter().any(|next| self.canonical(next) == consequent) {
+                    let antecedent = self.canonical(antecedent);
+                    if !visited.contains(antecedent) {
+                        tags.extend(self.synonyms(antecedent));
+                        stack.push(antecedent);
+                    }
+                }
+            }
+        }
+        tags.sort();
+        tags.dedup();
+        tags
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aliases() {
+        let mut config = Config::default();
+        config.parse("# invoices\ninvoices = invoice\n  facture = invoices \nbad line\ninvoice = facture\n");
+        assert_eq!(config.canonical("facture"), "invoice");
+        assert_eq!(config.canonical("invoice"), "invoice");
+        assert_eq!(config.canonical("photo"), "photo");
+        let mut synonyms = config.synonyms("invoices");
+        synonyms.sort();
+        assert_eq!(synonyms, vec!["facture", "invoice", "invoices"]);
+        assert!(config.insert_alias("invoice", "bill").is_err());
+    }
+
+    #[test]
+    fn test_implications() {
+        let mut config = Config::default();
+        config.parse("rs = rust\nrs => code\ncode => work\ninvoice => finance\nwork => rust\n");
+        assert!(config.implies("rust", "work"));
+        assert!(!config.implies("work", "rust"));
+        assert!(config.insert_implication("code", "code").is_err());
+        assert_eq!(config.matching_tags("work"), vec!["code", "rs", "rust", "work"]);
+        assert_eq!(config.matching_tags("code"), vec!["code", "rs", "rust"]);
+        assert_eq!(config.matching_tags("finance"), vec!["finance", "invoice"]);
+        assert_eq!(config.matching_tags("rust"), vec!["rs", "rust"]);
+        let mut tags : Vec<&str> = config.tags().into_iter().collect();
+        tags.sort();
+        assert_eq!(tags, vec!["code", "finance", "invoice", "rs", "rust", "work"]);
+    }
+
+    #[test]
+    fn test_save() {
+        use std::env::temp_dir;
+        use std::fs::{read_to_string, remove_file, write};
+        let path = format!("{}/tag_engine_config_{}", temp_dir().display(), std::process::id());
+        write(&path, "# invoices\n  facture = invoice \nbad line\n\n# rules\nrs => code\ninvoice => finance\n")
+            .unwrap();
+        let mut config = Config::load(path.clone()).unwrap();
+        assert_eq!(config.remove_implication("rs", "code"), Ok(true));
+        assert_eq!(config.add_alias("bill", "facture"), Ok(()));
+        assert_eq!(config.add_implication("code", "work"), Ok(()));
+        assert_eq!(read_to_string(&path).unwrap(), "# invoices\nfacture = invoice\nbad line\n\n# rules\n\
+            invoice => finance\nbill = invoice\ncode => work\n");
+        let reloaded = Config::load(path.clone()).unwrap();
+        assert_eq!(reloaded.matching_tags("finance"), vec!["bill", "facture", "finance", "invoice"]);
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_alias_after_implication() {
+        let mut config = Config::default();
+        config.parse("rs => code
+rs = rust
+");
+        assert!(config.implies("rust", "code"));
+        assert!(config.insert_implication("code", "rust").is_err());
+        assert_eq!(config.matching_tags("code"), vec!["code", "rs", "rust"]);
+
+        config.parse("work => go
+go => code
+");
+        assert!(config.insert_alias("code", "work").is_err());
+        assert_eq!(config.canonical("code"), "code");
+        assert!(config.insert_alias("golang", "go").is_ok());
+        assert!(config.implies("golang", "code"));
+    }
+}
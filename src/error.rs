@@ -0,0 +1,42 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use petgraph::graph::NodeIndex;
+
+/// Error of the graph layer. All of them concern a single entry, the
+/// daemon can log them and handle the next event.
+#[derive(Debug)]
+pub enum Error {
+    /// I/O error on a path, like an entry removed before its event is handled
+    Io(String, io::Error),
+    /// path that isn't valid UTF-8
+    InvalidPath(PathBuf),
+    /// local path without node in the graph
+    NotFound(String),
+    /// index of a node removed by a previous event
+    MissingNode(NodeIndex)
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref path, ref error) => write!(f, "{} : {}", path, error),
+            Error::InvalidPath(ref path) => write!(f, "{:?} isn't a valid UTF-8 path", path),
+            Error::NotFound(ref path) => write!(f, "{} isn't in the graph", path),
+            Error::MissingNode(index) => write!(f, "node {} isn't in the graph", index.index())
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(_, ref error) => Some(error),
+            _ => None
+        }
+    }
+}
@@ -1,9 +1,12 @@
 use std::collections::{HashMap, HashSet};
-use std::collections::hash_map::Entry::{Occupied, Vacant};
 use std::collections::hash_set::Difference;
 use std::collections::hash_map::RandomState;
-use std::fs::metadata;
-use std::fmt::{Debug, Formatter, Result};
+use std::fs::{metadata, Metadata};
+use std::fmt;
+use std::fmt::{Debug, Display, Formatter};
+use std::cmp::Ordering;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
 
 use walkdir::WalkDir;
 
@@ -13,46 +16,184 @@ use petgraph::Direction;
 
 extern crate tag_manager;
 
+use parse::parse_date;
+use error::{Error, Result};
+
+/// Separator of the hierarchical tags, like `lang/rust`.
+pub const TAG_SEPARATOR : char = '/';
+
 #[derive(Debug, Clone)]
 pub struct Nil;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum NodeKind {
     Tag,
     File,
     Directory
 }
 
+/// Metadata of a File node, `mtime` in seconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileMeta {
+    pub size : u64,
+    pub mtime : i64,
+    pub extension : Option<String>
+}
+
+/// Typed value of a `key=value` tag, a date being in days since the Unix
+/// epoch.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(f64),
+    Date(i64),
+    Text(String)
+}
+
+/// Structured form of a `key=value` Tag node.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attribute {
+    pub key : String,
+    pub value : Value
+}
+
 #[derive(Clone)]
 pub struct Node {
     pub name : String,
-    pub kind : NodeKind
+    pub kind : NodeKind,
+    pub meta : Option<FileMeta>,
+    pub attribute : Option<Attribute>,
+    /// inode of a File or Directory node, to follow it across renames
+    pub inode : Option<u64>,
+    /// files and directories of a Directory node by name, kept in sync with
+    /// its edges by `add_child`, `move_entry` and `remove_entries`
+    pub children : HashMap<String, NodeIndex>
+}
+
+/// Changes applied by `reconcile`, with local paths like `root/dir/file`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    pub added : Vec<String>,
+    pub removed : Vec<String>,
+    pub renamed : Vec<(String, String)>,
+    pub retagged : Vec<String>
 }
 
 pub type MyGraph = StableGraph<Node, Nil>;
 
+/// Graph of a tree with the index of its tags, shared by the watcher and
+/// the server behind a single lock.
+pub struct Index {
+    pub graph : MyGraph,
+    pub tags_index : HashMap<String, NodeIndex>,
+    pub root_index : NodeIndex,
+    /// directory containing the root, like `/data/` for `/data/finance`
+    pub base_path : String
+}
+
 impl Nil {
     fn new() -> Self { Self {} }
 }
 
+fn extension(name : &str) -> Option<String> {
+    Path::new(name).extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_string())
+}
+
+impl FileMeta {
+    fn new(name : &str, metadata : &Metadata) -> Self {
+        Self { size : metadata.len(), mtime : metadata.mtime(), extension : extension(name) }
+    }
+}
+
+impl Value {
+    /// Parse a finite number, then a `YYYY-MM-DD` date, else keep the text.
+    pub fn new(value : &str) -> Self {
+        match value.parse::<f64>() {
+            Ok(number) if number.is_finite() => Value::Number(number),
+            _ => match parse_date(value) {
+                Some(date) => Value::Date(date),
+                None => Value::Text(value.to_string())
+            }
+        }
+    }
+
+    /// `None` if the values are of different types.
+    pub fn compare(&self, other : &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => a.partial_cmp(b),
+            (Value::Date(a), Value::Date(b)) => Some(a.cmp(b)),
+            (Value::Text(a), Value::Text(b)) => Some(a.cmp(b)),
+            _ => None
+        }
+    }
+}
+
+impl Attribute {
+    /// Split a `key=value` tag, `None` if it isn't one.
+    pub fn new(tag : &str) -> Option<Self> {
+        let equal = tag.find('=')?;
+        let (key, value) = (&tag[..equal], &tag[equal + 1..]);
+        if key.is_empty() || value.is_empty() {
+            return None;
+        }
+        Some(Self { key : key.to_string(), value : Value::new(value) })
+    }
+}
+
 impl Node {
     fn new(name : String, kind : NodeKind) -> Self {
-        Self { name, kind }
+        let attribute = if kind == NodeKind::Tag { Attribute::new(&name) } else { None };
+        Self { name, kind, meta : None, attribute, inode : None, children : HashMap::new() }
+    }
+
+    fn new_file(name : String, metadata : &Metadata) -> Self {
+        let meta = Some(FileMeta::new(&name, metadata));
+        Self { name, kind : NodeKind::File, meta, attribute : None, inode : Some(metadata.ino()),
+            children : HashMap::new() }
     }
 
-    fn set_name(&mut self, name : String) {
+    pub fn set_name(&mut self, name : String) {
+        if let Some(ref mut meta) = self.meta {
+            meta.extension = extension(&name);
+        }
+        if self.kind == NodeKind::Tag {
+            self.attribute = Attribute::new(&name);
+        }
         self.name = name;
     }
 }
 
 impl Debug for Node {
-    fn fmt(&self, f: &mut Formatter) -> Result {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(f, "{:?} {:?}", self.kind, self.name)
     }
 }
 
+impl Report {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.renamed.is_empty() && self.retagged.is_empty()
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for path in &self.added {
+            writeln!(f, "added : {}", path)?;
+        }
+        for path in &self.removed {
+            writeln!(f, "removed : {}", path)?;
+        }
+        for (old_path, new_path) in &self.renamed {
+            writeln!(f, "renamed : {} -> {}", old_path, new_path)?;
+        }
+        for path in &self.retagged {
+            writeln!(f, "retagged : {}", path)?;
+        }
+        Ok(())
+    }
+}
+
 pub fn make_subgraph(root_index : NodeIndex, tags_index : &mut HashMap<String, NodeIndex>,
-    graph : &mut MyGraph, local_path : String, base_path : String) {
+    graph : &mut MyGraph, local_path : String, base_path : String) -> Result<()> {
     let mut path_vec : Vec<&str> = local_path.split('/').collect();
     let mut parent_index = root_index;
     let mut found = false;
@@ -66,31 +207,35 @@ pub fn make_subgraph(root_index : NodeIndex, tags_index : &mut HashMap<String, N
             build_path.push_str(entry);
             parent_index = find_parent(&graph, parent_index, entry, &mut found);
             if !found {
-                let new_node = if metadata(build_path.clone())
-                    .expect("make_subgraph, new_node, metadata").file_type().is_dir() {
-                    Node::new(String::from(entry), NodeKind::Directory)
+                let metadata = metadata(build_path.clone()).map_err(|error| Error::Io(build_path.clone(), error))?;
+                let new_node = if metadata.file_type().is_dir() {
+                    Node { inode : Some(metadata.ino()), ..Node::new(String::from(entry), NodeKind::Directory) }
                 }
-                else { Node::new(String::from(entry), NodeKind::File) };
+                else { Node::new_file(String::from(entry), &metadata) };
                 let new_node = graph.add_node(new_node);
-                graph.add_edge(parent_index, new_node, Nil::new());
-                update_tags(build_path.clone(), tags_index, graph, new_node);
+                add_child(graph, parent_index, new_node);
+                update_tags(build_path.clone(), tags_index, graph, new_node)?;
                 parent_index = new_node;
             }
         }
     }
+    Ok(())
 }
 
+/// Build the graph of `path_root`, an entry failing to be added is logged
+/// and skipped.
 pub fn make_graph(path_root : String, base_path : String)
-    -> (MyGraph, HashMap<String, NodeIndex>, NodeIndex) {
+    -> Result<(MyGraph, HashMap<String, NodeIndex>, NodeIndex)> {
     let mut graph : MyGraph = StableGraph::new();
     let mut tags_index = HashMap::new();
     let local_root = local_path(&mut path_root.clone(),
         base_path.clone());
+    let inode = metadata(&path_root).map_err(|error| Error::Io(path_root.clone(), error))?.ino();
     let root_index = graph.add_node(
-        Node::new(local_root, NodeKind::Directory)
+        Node { inode : Some(inode), ..Node::new(local_root, NodeKind::Directory) }
     );
     update_tags(path_root.clone(), &mut tags_index,
-        &mut graph, root_index);
+        &mut graph, root_index)?;
     let mut is_root = true;
 
     for entry in WalkDir::new(path_root).into_iter()
@@ -101,111 +246,268 @@ pub fn make_graph(path_root : String, base_path : String)
         }
         let mut path = entry.path().display().to_string();
         let path = local_path(&mut path, base_path.clone());
-        make_subgraph(root_index, &mut tags_index, &mut graph,
-            path, base_path.clone());
+        if let Err(error) = make_subgraph(root_index, &mut tags_index, &mut graph,
+            path, base_path.clone()) {
+            eprintln!("make_graph, {}", error);
+        }
+    }
+    Ok((graph, tags_index, root_index))
+}
+
+/// Local path of a File or Directory node, like `root/dir/file`.
+pub fn entry_local_path(graph : &MyGraph, entry_index : NodeIndex) -> String {
+    let mut names = vec![graph.node_weight(entry_index).unwrap().name.as_str()];
+    let mut current = entry_index;
+    while let Some(parent_index) = get_parent_index(graph, current) {
+        names.push(&graph.node_weight(parent_index).unwrap().name);
+        current = parent_index;
+    }
+    names.reverse();
+    names.join("/")
+}
+
+/// Diff a graph, like one read from a snapshot, against the tree of
+/// `path_root`. Entries found elsewhere with the same inode are moved, new
+/// entries go through `make_subgraph` and vanished ones through
+/// `remove_entries`. The tags and metadata are read again only for the
+/// entries modified or changed since `since`. An entry failing to be
+/// reconciled is logged and skipped.
+pub fn reconcile(root_index : NodeIndex, tags_index : &mut HashMap<String, NodeIndex>,
+    graph : &mut MyGraph, path_root : String, base_path : String, since : i64) -> Report {
+    let mut reconciliation = Reconciliation {
+        since, inodes : HashMap::new(), seen : HashSet::new(), report : Report::default()
+    };
+    for index in graph.node_indices() {
+        let node = graph.node_weight(index).unwrap();
+        match (&node.kind, node.inode) {
+            (&NodeKind::Tag, _) | (_, None) => (),
+            (_, Some(inode)) => { reconciliation.inodes.insert(inode, index); }
+        }
+    }
+    for entry in WalkDir::new(path_root).into_iter()
+        .filter_map(|e| e.ok()) {
+        let path = entry.path().display().to_string();
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue
+        };
+        if let Err(error) = reconciliation.entry(root_index, tags_index, graph, path, &metadata, base_path.clone()) {
+            eprintln!("reconcile, {}", error);
+        }
+    }
+    let Reconciliation { seen, mut report, .. } = reconciliation;
+    let vanished : Vec<NodeIndex> = graph.node_indices().filter(|index| {
+        graph.node_weight(*index).unwrap().kind != NodeKind::Tag && !seen.contains(index)
+    }).collect();
+    for entry_index in vanished {
+        // may already be removed with its parent directory
+        if graph.node_weight(entry_index).is_some() {
+            report.removed.push(entry_local_path(graph, entry_index));
+            if let Err(error) = remove_entries(entry_index, graph, tags_index) {
+                eprintln!("reconcile, {}", error);
+            }
+        }
+    }
+    report
+}
+
+/// State of `reconcile` while walking the tree.
+struct Reconciliation {
+    since : i64,
+    /// inodes of the entries of the graph before any change
+    inodes : HashMap<u64, NodeIndex>,
+    seen : HashSet<NodeIndex>,
+    report : Report
+}
+
+impl Reconciliation {
+    fn entry(&mut self, root_index : NodeIndex, tags_index : &mut HashMap<String, NodeIndex>, graph : &mut MyGraph,
+        path : String, metadata : &Metadata, base_path : String) -> Result<()> {
+        let local = local_path(&mut path.clone(), base_path.clone());
+        let changed = metadata.mtime() >= self.since || metadata.ctime() >= self.since;
+        let kind = if metadata.is_dir() { NodeKind::Directory } else { NodeKind::File };
+        let is_same = |graph : &MyGraph, index : NodeIndex| {
+            let node = graph.node_weight(index).unwrap();
+            // nodes loaded without an inode can't be told apart, trust the path
+            node.kind == kind && match node.inode {
+                Some(inode) => inode == metadata.ino(),
+                None => true
+            }
+        };
+        let mut entry_index = find_node_index(root_index, graph, &local);
+        // the node at this path, if any, is another entry
+        if let Some(index) = entry_index.filter(|index| !is_same(graph, *index)) {
+            self.report.removed.push(entry_local_path(graph, index));
+            remove_entries(index, graph, tags_index)?;
+            entry_index = None;
+        }
+        let entry_index = match entry_index {
+            Some(index) => index,
+            None => {
+                // indexes of removed nodes may be reused, check the node is still the same
+                let moved = self.inodes.get(&metadata.ino()).cloned().filter(|index| {
+                    graph.node_weight(*index).is_some() && !self.seen.contains(index) && is_same(graph, *index)
+                });
+                match moved {
+                    Some(index) => {
+                        self.report.renamed.push((entry_local_path(graph, index), local.clone()));
+                        move_entry(root_index, index, graph, tags_index, local.clone())?;
+                        index
+                    },
+                    None => {
+                        make_subgraph(root_index, tags_index, graph, local.clone(), base_path)?;
+                        self.report.added.push(local.clone());
+                        if let Some(index) = find_node_index(root_index, graph, &local) {
+                            self.seen.insert(index);
+                        }
+                        return Ok(());
+                    }
+                }
+            }
+        };
+        self.seen.insert(entry_index);
+        if changed {
+            let tags = get_tags(graph, entry_index);
+            update_metadata(path.clone(), graph, entry_index);
+            update_tags(path, tags_index, graph, entry_index)?;
+            if get_tags(graph, entry_index) != tags {
+                self.report.retagged.push(local);
+            }
+        }
+        Ok(())
     }
-    (graph, tags_index, root_index)
 }
 
 pub fn local_path(absolute_path : &mut String, base_path : String) -> String {
     absolute_path.split_off(base_path.len())
 }
 
-pub fn get_node_index(root_index : NodeIndex, graph : &MyGraph, path : String) -> NodeIndex {
-    let mut path_vec : Vec<&str> = path.split('/').collect();
+/// Link a File or Directory node to its parent directory.
+pub fn add_child(graph : &mut MyGraph, parent_index : NodeIndex, child_index : NodeIndex) {
+    let name = graph.node_weight(child_index).expect("add_child, child").name.clone();
+    graph.node_weight_mut(parent_index).expect("add_child, parent").children.insert(name, child_index);
+    graph.add_edge(parent_index, child_index, Nil::new());
+}
+
+/// Node of a local path like `root/dir/file`, `None` if the path doesn't
+/// exist in the graph.
+pub fn find_node_index(root_index : NodeIndex, graph : &MyGraph, path : &str) -> Option<NodeIndex> {
+    let mut path_vec = path.split('/');
+    match path_vec.next() {
+        Some(root_name) if root_name == graph.node_weight(root_index).unwrap().name => (),
+        _ => return None
+    }
     let mut parent_index = root_index;
     let mut found = false;
-    if !path_vec.is_empty() {
-        // remove path_root
-        path_vec.remove(0);
-        for entry in path_vec {
-            parent_index = find_parent(&graph, parent_index, entry, &mut found);
+    for entry in path_vec {
+        parent_index = find_parent(graph, parent_index, entry, &mut found);
+        if !found {
+            return None;
         }
     }
-    parent_index
+    Some(parent_index)
 }
 
-pub fn move_entry(root_index : NodeIndex, entry_index : NodeIndex, graph : &mut MyGraph, new_path : String) {
-    let mut parent_index = entry_index;
-    for neighbor_index in graph.neighbors_directed(entry_index, Direction::Incoming) {
-        match graph.node_weight(neighbor_index) {
-            Some(data) => {
-                match data.kind {
-                    NodeKind::Directory => {
-                        parent_index = neighbor_index;
-                        break;
-                    },
-                    _ => ()
-                }
-            },
-            None => ()
-        }
+pub fn get_parent_index(graph : &MyGraph, entry_index : NodeIndex) -> Option<NodeIndex> {
+    graph.neighbors_directed(entry_index, Direction::Incoming)
+        .find(|index| graph.node_weight(*index).unwrap().kind == NodeKind::Directory)
+}
+
+/// Move an entry to `new_path`, whose parent directory must be in the graph.
+/// An entry already at `new_path` is overwritten and removed.
+pub fn move_entry(root_index : NodeIndex, entry_index : NodeIndex, graph : &mut MyGraph,
+    tags_index : &mut HashMap<String, NodeIndex>, new_path : String) -> Result<()> {
+    let old_name = graph.node_weight(entry_index).ok_or(Error::MissingNode(entry_index))?.name.clone();
+    let mut path_vec : Vec<&str> = new_path.split('/').collect();
+    let new_name = path_vec.pop().expect("move_entry, path_vec.pop()").to_string();
+    let new_parent_path = path_vec.join("/");
+    let new_parent_index = find_node_index(root_index, graph, &new_parent_path)
+        .ok_or(Error::NotFound(new_parent_path))?;
+    let replaced_index = graph.node_weight(new_parent_index).and_then(|node| node.children.get(&new_name)).cloned();
+    match replaced_index {
+        Some(replaced_index) if replaced_index != entry_index =>
+            remove_entries(replaced_index, graph, tags_index)?,
+        _ => ()
     }
-    let new_parent_index = get_node_index(root_index, graph, new_path.clone());
-    if parent_index == new_parent_index {
-        let mut path_vec : Vec<&str> = new_path.split('/').collect();
-        let new_name = path_vec.pop().expect("move_entry, path_vec.pop()").to_string();
-        let node = graph.node_weight_mut(entry_index)
-            .expect("move_entry, graph.node_weight_mut");
-        node.set_name(new_name);
-    }
-    else {
-        let edge = graph.find_edge(parent_index, entry_index);
-        match edge {
-            Some(edge_index) => { graph.remove_edge(edge_index); },
-            None => ()
+    let parent_index = get_parent_index(graph, entry_index).unwrap_or(entry_index);
+    graph.node_weight_mut(parent_index).expect("move_entry, parent").children.remove(&old_name);
+    if let Some(edge_index) = graph.find_edge(parent_index, entry_index) {
+        graph.remove_edge(edge_index);
+    }
+    // the entry may be moved and renamed at once
+    let node = graph.node_weight_mut(entry_index)
+        .expect("move_entry, graph.node_weight_mut");
+    node.set_name(new_name);
+    add_child(graph, new_parent_index, entry_index);
+    Ok(())
+}
+
+/// Refresh the inode of an entry, and the size, modification time and
+/// extension of a File node.
+pub fn update_metadata(path : String, graph : &mut MyGraph, entry_index : NodeIndex) {
+    if let Ok(metadata) = metadata(path) {
+        let node = graph.node_weight_mut(entry_index).expect("update_metadata, graph.node_weight_mut");
+        node.inode = Some(metadata.ino());
+        if node.kind == NodeKind::File {
+            node.meta = Some(FileMeta::new(&node.name, &metadata));
         }
-        graph.add_edge(new_parent_index, entry_index, Nil::new());
     }
 }
 
-pub fn remove_entries(entry_index : NodeIndex, graph : &mut MyGraph, tags_index : &mut HashMap<String, NodeIndex>) {
+pub fn remove_entries(entry_index : NodeIndex, graph : &mut MyGraph, tags_index : &mut HashMap<String, NodeIndex>)
+    -> Result<()> {
+    if graph.node_weight(entry_index).is_none() {
+        return Err(Error::MissingNode(entry_index));
+    }
     let mut entries_index = Vec::new();
     let mut check_tags_index = Vec::new();
     entries_to_remove(entry_index, graph, &mut entries_index, &mut check_tags_index);
+    if let Some(parent_index) = get_parent_index(graph, entry_index) {
+        let name = graph.node_weight(entry_index).unwrap().name.clone();
+        graph.node_weight_mut(parent_index).unwrap().children.remove(&name);
+    }
     for index in entries_index.into_iter().rev() {
         graph.remove_node(index);
     }
     for tag_index in check_tags_index {
-        if graph.edges(tag_index).count() == 0 {
-            tags_index.remove(&graph.node_weight(tag_index).unwrap().name);
-            graph.remove_node(tag_index);
+        // may already be removed as the unused parent of another tag
+        if graph.node_weight(tag_index).is_some() {
+            remove_unused_tag(tag_index, tags_index, graph);
         }
     }
+    Ok(())
 }
 
 fn find_parent(graph : &MyGraph, index : NodeIndex, entry : &str, found : &mut bool) -> NodeIndex {
-    for neighbor_index in graph.neighbors(index) {
-        match graph.node_weight(neighbor_index) {
-            Some(data) => {
-                match data.kind {
-                    NodeKind::File | NodeKind::Directory => {
-                        if String::from(entry) == data.name {
-                            *found = true;
-                            return neighbor_index;
-                        }
-                    },
-                    _ => ()
-                }
-            },
-            None => ()
+    match graph.node_weight(index).and_then(|data| data.children.get(entry)) {
+        Some(&child_index) => {
+            *found = true;
+            child_index
+        },
+        None => {
+            *found = false;
+            index
         }
     }
-    *found = false;
-    index
 }
 
 fn entries_to_remove(entry_index : NodeIndex, graph : &MyGraph,
     entries_index : &mut Vec<NodeIndex>, check_tags_index : &mut Vec<NodeIndex>) {
     entries_index.push(entry_index);
+    for neighbor_index in graph.neighbors_directed(entry_index, Direction::Incoming) {
+        if let Some(data) = graph.node_weight(neighbor_index) {
+            if data.kind == NodeKind::Tag {
+                check_tags_index.push(neighbor_index);
+            }
+        }
+    }
     for neighbor_index in graph.neighbors_directed(entry_index, Direction::Outgoing) {
         match graph.node_weight(neighbor_index) {
             Some(data) => {
                 match data.kind {
-                    NodeKind::Directory =>
+                    NodeKind::File | NodeKind::Directory =>
                         entries_to_remove(neighbor_index, graph, entries_index, check_tags_index),
-                    NodeKind::File => entries_index.push(neighbor_index),
-                    NodeKind::Tag => check_tags_index.push(neighbor_index)
+                    NodeKind::Tag => ()
                 }
             },
             None => ()
@@ -217,7 +519,10 @@ fn entries_to_remove(entry_index : NodeIndex, graph : &MyGraph,
 
 pub fn update_tags(path : String,
     tags_index : &mut HashMap<String, NodeIndex>,
-    graph : &mut MyGraph, entry_index : NodeIndex) {
+    graph : &mut MyGraph, entry_index : NodeIndex) -> Result<()> {
+    if graph.node_weight(entry_index).is_none() {
+        return Err(Error::MissingNode(entry_index));
+    }
     let existent_tags = get_tags(graph, entry_index);
     let fresh_tags = match tag_manager::get_tags(&path) {
         Some(tags) => tags,
@@ -227,9 +532,11 @@ pub fn update_tags(path : String,
         tags_index, graph, entry_index);
     add_tags(fresh_tags.difference(&existent_tags),
         tags_index, graph, entry_index);
+    Ok(())
 }
 
-fn get_tags(graph : &MyGraph, tag_index : NodeIndex) -> HashSet<String> {
+/// Tags of a File or Directory node, as written in its attributes.
+pub fn get_tags(graph : &MyGraph, tag_index : NodeIndex) -> HashSet<String> {
     let mut tags = HashSet::new();
     for neighbor_index in graph.neighbors_directed(tag_index, Direction::Incoming) {
         match graph.node_weight(neighbor_index) {
@@ -245,39 +552,246 @@ fn get_tags(graph : &MyGraph, tag_index : NodeIndex) -> HashSet<String> {
     tags
 }
 
+/// Parent of a hierarchical tag, `lang` for `lang/rust` and `lang/version=2`.
+/// Only the key of a `key=value` tag is split, the value being opaque.
+pub fn parent_tag(tag : &str) -> Option<&str> {
+    let key = match tag.find('=') {
+        Some(equal) => &tag[..equal],
+        None => tag
+    };
+    match key.rfind(TAG_SEPARATOR) {
+        Some(index) if index > 0 && index < key.len() - 1 => Some(&tag[..index]),
+        _ => None
+    }
+}
+
+/// Files and directories directly tagged by a Tag node, without its child
+/// tags.
+pub fn tagged_entries<'a>(graph : &'a MyGraph, tag_index : NodeIndex) -> impl Iterator<Item = NodeIndex> + 'a {
+    graph.neighbors_directed(tag_index, Direction::Outgoing)
+        .filter(move |index| graph.node_weight(*index).unwrap().kind != NodeKind::Tag)
+}
+
+/// Insert a Tag node and all its descendant tags in `tags`.
+pub fn tag_descendants(graph : &MyGraph, tag_index : NodeIndex, tags : &mut HashSet<NodeIndex>) {
+    tags.insert(tag_index);
+    for neighbor_index in graph.neighbors_directed(tag_index, Direction::Outgoing) {
+        if graph.node_weight(neighbor_index).unwrap().kind == NodeKind::Tag {
+            tag_descendants(graph, neighbor_index, tags);
+        }
+    }
+}
+
+/// Index of the Tag node, created with its missing parents if needed.
+fn tag_node_index(tag : &str, tags_index : &mut HashMap<String, NodeIndex>, graph : &mut MyGraph) -> NodeIndex {
+    if let Some(&tag_index) = tags_index.get(tag) {
+        return tag_index;
+    }
+    let new_node_tag = graph.add_node(Node::new(tag.to_string(), NodeKind::Tag));
+    tags_index.insert(tag.to_string(), new_node_tag);
+    if let Some(parent) = parent_tag(tag) {
+        let parent_index = tag_node_index(parent, tags_index, graph);
+        graph.add_edge(parent_index, new_node_tag, Nil::new());
+    }
+    new_node_tag
+}
+
+/// Remove a Tag node without entries nor child tags, then its parents if
+/// they became unused.
+fn remove_unused_tag(tag_index : NodeIndex, tags_index : &mut HashMap<String, NodeIndex>, graph : &mut MyGraph) {
+    if graph.edges(tag_index).count() == 0 {
+        let parent_index = graph.neighbors_directed(tag_index, Direction::Incoming).next();
+        tags_index.remove(&graph.node_weight(tag_index).unwrap().name);
+        graph.remove_node(tag_index);
+        if let Some(parent_index) = parent_index {
+            remove_unused_tag(parent_index, tags_index, graph);
+        }
+    }
+}
+
 fn add_tags(tags_to_add : Difference<String, RandomState>, tags_index : &mut HashMap<String, NodeIndex>,
     graph : &mut MyGraph, entry_index : NodeIndex) {
     for tag in tags_to_add {
-        match tags_index.entry(tag.clone()) {
-            Vacant(entry) => {
-                let new_node_tag = graph.add_node(Node::new(tag.clone(), NodeKind::Tag));
-                entry.insert(new_node_tag);
-                graph.add_edge(new_node_tag, entry_index, Nil::new());
-            },
-            Occupied(entry) => {
-                let &tag_index = entry.get();
-                graph.add_edge(tag_index, entry_index, Nil::new());
-            }
-        }
+        let tag_index = tag_node_index(tag, tags_index, graph);
+        graph.add_edge(tag_index, entry_index, Nil::new());
     }
 }
 
 fn remove_tags(tags_to_remove : Difference<String, RandomState>, tags_index : &mut HashMap<String, NodeIndex>,
     graph : &mut MyGraph, entry_index : NodeIndex) {
     for tag in tags_to_remove {
-        match tags_index.entry(tag.clone()) {
-            Occupied(entry) => {
-                let &tag_index = entry.get();
-                    match graph.find_edge(tag_index, entry_index) {
-                        Some(edge) => { graph.remove_edge(edge); },
-                        None => ()
-                    }
-                    if graph.edges(tag_index).count() == 0 {
-                        entry.remove();
-                        graph.remove_node(tag_index);
-                    }
-            },
-            Vacant(_) => ()
+        if let Some(tag_index) = tags_index.get(tag).cloned() {
+            if let Some(edge) = graph.find_edge(tag_index, entry_index) {
+                graph.remove_edge(edge);
+            }
+            remove_unused_tag(tag_index, tags_index, graph);
         }
     }
 }
+
+/// Index of `/data/root` for the tests. `entries` are local paths like
+/// `root/dir/file`, parents first and directories ending with `/`, each with
+/// the tags it holds.
+#[cfg(test)]
+pub fn test_index(entries : &[(&str, &[&str])]) -> Index {
+    let mut graph = MyGraph::new();
+    let mut tags_index = HashMap::new();
+    let root_index = graph.add_node(Node::new(String::from("root"), NodeKind::Directory));
+    for &(path, tags) in entries {
+        let (path, kind) = match path.strip_suffix('/') {
+            Some(path) => (path, NodeKind::Directory),
+            None => (path, NodeKind::File)
+        };
+        let entry_index = match find_node_index(root_index, &graph, path) {
+            Some(entry_index) => entry_index,
+            None => {
+                let (parent, name) = path.rsplit_once('/').expect("test_index, path");
+                let parent_index = find_node_index(root_index, &graph, parent).expect("test_index, parent");
+                let entry_index = graph.add_node(Node::new(name.to_string(), kind));
+                add_child(&mut graph, parent_index, entry_index);
+                entry_index
+            }
+        };
+        let tags : HashSet<String> = tags.iter().map(|tag| tag.to_string()).collect();
+        add_tags(tags.difference(&HashSet::new()), &mut tags_index, &mut graph, entry_index);
+    }
+    Index { graph, tags_index, root_index, base_path : String::from("/data/") }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tags : &[&str]) -> HashSet<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn test_hierarchical_tags() {
+        let mut graph = MyGraph::new();
+        let mut tags_index = HashMap::new();
+        let file = graph.add_node(Node::new(String::from("file"), NodeKind::File));
+        let none = tags(&[]);
+        let rust = tags(&["lang/rust", "lang/python/3"]);
+        add_tags(rust.difference(&none), &mut tags_index, &mut graph, file);
+        let mut names : Vec<&String> = tags_index.keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["lang", "lang/python", "lang/python/3", "lang/rust"]);
+        assert_eq!(get_tags(&graph, file), rust);
+        let lang = tags_index["lang"];
+        let mut descendants = HashSet::new();
+        tag_descendants(&graph, lang, &mut descendants);
+        assert_eq!(descendants.len(), 4);
+        assert_eq!(tagged_entries(&graph, lang).count(), 0);
+
+        let attributes = tags(&["client=acme/emea", "lang/version=2"]);
+        add_tags(attributes.difference(&none), &mut tags_index, &mut graph, file);
+        assert!(!tags_index.contains_key("client=acme"));
+        assert_eq!(graph.node_weight(tags_index["lang"]).unwrap().attribute, None);
+        let mut descendants = HashSet::new();
+        tag_descendants(&graph, tags_index["client=acme/emea"], &mut descendants);
+        assert_eq!(descendants.len(), 1);
+        remove_tags(attributes.difference(&none), &mut tags_index, &mut graph, file);
+
+        let python = tags(&["lang/python/3"]);
+        remove_tags(python.difference(&none), &mut tags_index, &mut graph, file);
+        let mut names : Vec<&String> = tags_index.keys().collect();
+        names.sort();
+        assert_eq!(names, vec!["lang", "lang/rust"]);
+        remove_tags(rust.difference(&none), &mut tags_index, &mut graph, file);
+        assert!(tags_index.is_empty());
+        assert_eq!(graph.node_count(), 1);
+    }
+
+    #[test]
+    fn test_reconcile() {
+        use std::env::temp_dir;
+        use std::fs::{create_dir_all, remove_dir_all, remove_file, rename, write};
+        let base = format!("{}/tag_engine_reconcile_{}/", temp_dir().display(), std::process::id());
+        let root = format!("{}root", base);
+        let _ = remove_dir_all(&base);
+        create_dir_all(format!("{}/d/e", root)).unwrap();
+        write(format!("{}/a", root), "a").unwrap();
+        write(format!("{}/d/b", root), "b").unwrap();
+        let (mut graph, mut tags_index, root_index) = make_graph(root.clone(), base.clone()).unwrap();
+        let a = find_node_index(root_index, &graph, "root/a").unwrap();
+
+        rename(format!("{}/d", root), format!("{}/r", root)).unwrap();
+        rename(format!("{}/a", root), format!("{}/r/e/c", root)).unwrap();
+        remove_file(format!("{}/r/b", root)).unwrap();
+        create_dir_all(format!("{}/n", root)).unwrap();
+        let mut report = reconcile(root_index, &mut tags_index, &mut graph, root.clone(), base.clone(), 0);
+        remove_dir_all(&base).unwrap();
+        report.renamed.sort();
+        assert_eq!(report, Report {
+            added : vec![String::from("root/n")],
+            removed : vec![String::from("root/r/b")],
+            renamed : vec![(String::from("root/a"), String::from("root/r/e/c")),
+                (String::from("root/d"), String::from("root/r"))],
+            retagged : vec![]
+        });
+        assert_eq!(find_node_index(root_index, &graph, "root/r/e/c"), Some(a));
+        assert_eq!(entry_local_path(&graph, a), "root/r/e/c");
+        assert_eq!(graph.node_count(), 5);
+        let mut children : Vec<&String> = graph.node_weight(root_index).unwrap().children.keys().collect();
+        children.sort();
+        assert_eq!(children, vec!["n", "r"]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut graph = MyGraph::new();
+        let mut tags_index = HashMap::new();
+        let root = graph.add_node(Node::new(String::from("root"), NodeKind::Directory));
+        let file = graph.add_node(Node::new(String::from("file"), NodeKind::File));
+        add_child(&mut graph, root, file);
+        match make_subgraph(root, &mut tags_index, &mut graph, String::from("root/missing"),
+            String::from("/nonexistent/")) {
+            Err(Error::Io(path, _)) => assert_eq!(path, "/nonexistent/root/missing"),
+            result => panic!("{:?}", result)
+        }
+        match move_entry(root, file, &mut graph, &mut tags_index, String::from("root/dir/file")) {
+            Err(Error::NotFound(path)) => assert_eq!(path, "root/dir"),
+            result => panic!("{:?}", result)
+        }
+        assert_eq!(find_node_index(root, &graph, "root/file"), Some(file));
+        assert!(remove_entries(file, &mut graph, &mut tags_index).is_ok());
+        match remove_entries(file, &mut graph, &mut tags_index) {
+            Err(Error::MissingNode(index)) => assert_eq!(index, file),
+            result => panic!("{:?}", result)
+        }
+        assert!(graph.node_weight(root).unwrap().children.is_empty());
+    }
+
+    #[test]
+    fn test_move_overwrite() {
+        let mut graph = MyGraph::new();
+        let mut tags_index = HashMap::new();
+        let root = graph.add_node(Node::new(String::from("root"), NodeKind::Directory));
+        let a = graph.add_node(Node::new(String::from("a"), NodeKind::File));
+        let b = graph.add_node(Node::new(String::from("b"), NodeKind::File));
+        add_child(&mut graph, root, a);
+        add_child(&mut graph, root, b);
+        add_tags(tags(&["old"]).difference(&tags(&[])), &mut tags_index, &mut graph, b);
+
+        move_entry(root, a, &mut graph, &mut tags_index, String::from("root/b")).unwrap();
+        assert!(graph.node_weight(b).is_none());
+        assert!(tags_index.is_empty());
+        assert_eq!(find_node_index(root, &graph, "root/b"), Some(a));
+        assert_eq!(graph.neighbors_directed(root, Direction::Outgoing).count(), 1);
+        remove_entries(a, &mut graph, &mut tags_index).unwrap();
+        assert_eq!(graph.node_count(), 1);
+    }
+
+    #[test]
+    fn test_parent_tag() {
+        assert_eq!(parent_tag("lang/rust"), Some("lang"));
+        assert_eq!(parent_tag("lang/python/3"), Some("lang/python"));
+        assert_eq!(parent_tag("lang"), None);
+        assert_eq!(parent_tag("/lang"), None);
+        assert_eq!(parent_tag("lang/"), None);
+        assert_eq!(parent_tag("lang/version=2"), Some("lang"));
+        assert_eq!(parent_tag("client=acme/emea"), None);
+        assert_eq!(parent_tag("path=/c/b"), None);
+    }
+}
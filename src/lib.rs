@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::path::Path;
 
 extern crate walkdir;
 
@@ -7,50 +8,80 @@ use petgraph::graph::NodeIndex;
 
 extern crate notify;
 use notify::DebouncedEvent;
-use notify::DebouncedEvent::{Create, Chmod, Remove, Rename};
+use notify::DebouncedEvent::{Create, Chmod, Write, Remove, Rename};
 
 extern crate tag_manager;
 
+extern crate regex;
+
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+
+pub mod error;
+pub use error::Error;
+use error::Result;
+
 pub mod graph;
-use graph::{MyGraph, local_path, make_subgraph, get_node_index, update_tags, move_entry, remove_entries};
+use graph::{MyGraph, local_path, make_subgraph, find_node_index, update_tags, update_metadata,
+    move_entry, remove_entries};
 
 pub mod server;
 pub mod parse;
+pub mod planner;
+pub mod config;
+pub mod snapshot;
+
+fn path_to_string(path : &Path) -> Result<String> {
+    path.to_str().map(|path| path.to_string()).ok_or_else(|| Error::InvalidPath(path.to_path_buf()))
+}
+
+fn entry_index(root_index : NodeIndex, graph : &MyGraph, local : &str) -> Result<NodeIndex> {
+    find_node_index(root_index, graph, local).ok_or_else(|| Error::NotFound(local.to_string()))
+}
 
 pub fn dispatcher(event : DebouncedEvent, tags_index : &mut HashMap<String, NodeIndex>,
-    graph : &mut MyGraph, root_index : NodeIndex, base : String) {
+    graph : &mut MyGraph, root_index : NodeIndex, base : String) -> Result<()> {
     match event {
         Create(path) => {
-            let mut path = path.as_path().to_str().expect("dispatcher, create, path").to_string();
+            let mut path = path_to_string(&path)?;
             let local = local_path(&mut path, base.clone());
             println!("========== CREATE  : {:?} ==========", local);
-            make_subgraph(root_index, tags_index, graph, local, base.clone());
+            make_subgraph(root_index, tags_index, graph, local, base.clone())?;
         },
         Chmod(path) => {
-            let mut path = path.as_path().to_str().expect("dispatcher, chmod, path").to_string();
+            let path = path_to_string(&path)?;
             let local = local_path(&mut path.clone(), base);
             println!("========== CHMOD : {:?} ==========", local);
-            let entry_index = get_node_index(root_index, graph, local);
-            update_tags(path, tags_index, graph, entry_index);
+            let entry_index = entry_index(root_index, graph, &local)?;
+            update_metadata(path.clone(), graph, entry_index);
+            update_tags(path, tags_index, graph, entry_index)?;
+        },
+        Write(path) => {
+            let path = path_to_string(&path)?;
+            let local = local_path(&mut path.clone(), base);
+            println!("========== WRITE : {:?} ==========", local);
+            let entry_index = entry_index(root_index, graph, &local)?;
+            update_metadata(path, graph, entry_index);
         },
         Remove(path) => {
-            let mut path = path.as_path().to_str().expect("dispatcher, remove, path").to_string();
+            let path = path_to_string(&path)?;
             let local = local_path(&mut path.clone(), base);
             println!("========== REMOVE : {:?} ==========", local);
-            let entry_index = get_node_index(root_index, graph, local);
-            remove_entries(entry_index, graph, tags_index);
+            let entry_index = entry_index(root_index, graph, &local)?;
+            remove_entries(entry_index, graph, tags_index)?;
         },
         Rename(old_path, new_path) => {
-            let mut old_path = old_path.as_path().to_str()
-                .expect("dispatcher, rename, old_path").to_string();
-            let new_path = new_path.as_path().to_str()
-                .expect("dispatcher, rename, new_path").to_string();
+            let old_path = path_to_string(&old_path)?;
+            let new_path = path_to_string(&new_path)?;
             let old_local = local_path(&mut old_path.clone(), base.clone());
             let new_local = local_path(&mut new_path.clone(), base.clone());
             println!("========== RENAME, old_path : {:?}, new_path : {:?} ==========", old_local, new_local);
-            let entry_index = get_node_index(root_index, graph, old_local);
-            move_entry(root_index, entry_index, graph, new_local);
+            let entry_index = entry_index(root_index, graph, &old_local)?;
+            move_entry(root_index, entry_index, graph, tags_index, new_local)?;
         }
         _ => ()
     }
+    Ok(())
 }
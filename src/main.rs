@@ -2,8 +2,8 @@ use std::io::prelude::*;
 use std::fs::File;
 use std::process::Command;
 use std::thread;
-use std::sync::{Mutex, Arc};
-use std::sync::mpsc::channel;
+use std::sync::{RwLock, Arc};
+use std::sync::mpsc::{channel, RecvTimeoutError};
 use std::time::{Duration, Instant};
 
 extern crate petgraph;
@@ -11,12 +11,15 @@ use petgraph::dot::{Dot, Config};
 
 extern crate notify;
 use notify::{Watcher, RecursiveMode, watcher};
+use notify::DebouncedEvent;
 use notify::DebouncedEvent::{Create, Chmod, Remove, Rename};
 
 extern crate tag_manager;
 
 extern crate tag_engine;
-use tag_engine::graph::MyGraph;
+use tag_engine::graph::{MyGraph, Index};
+use tag_engine::config::config_path;
+use tag_engine::snapshot;
 
 use std::path::Path;
 use std::process::exit;
@@ -24,6 +27,10 @@ use std::process::exit;
 extern crate clap;
 use clap::{App, Arg};
 
+const SNAPSHOT_INTERVAL : Duration = Duration::from_secs(60);
+const DEFAULT_WORKERS : &str = "4";
+const DEFAULT_SOCKET : &str = "/tmp/tag_engine";
+
 fn split_root_path(absolute_path : &mut String) -> (String, String) {
     let clone = absolute_path.clone();
     let mut path_vec : Vec<&str> = clone.split('/').collect();
@@ -47,6 +54,20 @@ fn main() {
             .takes_value(true).required(true).multiple(false))
         .arg(Arg::with_name("debug")
             .short("-d").long("--debug").required(false).multiple(false))
+        .arg(Arg::with_name("inherit")
+            .short("-i").long("--inherit").required(false).multiple(false)
+            .help("The tags of a directory apply to all its descendants"))
+        .arg(Arg::with_name("snapshot")
+            .short("-s").long("--snapshot").required(false).multiple(false)
+            .help("Start from the snapshot of the graph, saved periodically"))
+        .arg(Arg::with_name("workers")
+            .short("-w").long("--workers").takes_value(true).required(false).multiple(false)
+            .default_value(DEFAULT_WORKERS)
+            .help("Number of threads handling the requests"))
+        .arg(Arg::with_name("socket")
+            .long("--socket").takes_value(true).required(false).multiple(false)
+            .default_value(DEFAULT_SOCKET)
+            .help("Path of the Unix socket of the server"))
         .get_matches();
 
     let absolute_path_root = matches.value_of("path").unwrap();
@@ -64,9 +85,57 @@ fn main() {
         exit(1);
     }
 
-    let (base_path, _) = split_root_path(&mut absolute_path_root.to_string());
+    let workers = match matches.value_of("workers").unwrap().parse::<usize>() {
+        Ok(workers) if workers > 0 => workers,
+        _ => {
+            eprintln!("The number of workers must be a positive integer");
+            exit(1);
+        }
+    };
+
+    let (base_path, root_name) = split_root_path(&mut absolute_path_root.to_string());
+    let mut config = match tag_engine::config::Config::load(config_path(&base_path, &root_name)) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("Can't read the configuration : {}", error);
+            exit(1);
+        }
+    };
+    config.set_inherit(matches.is_present("inherit"));
     let now = Instant::now();
-    let (graph, tags_index, root_index) = tag_engine::graph::make_graph(String::from(absolute_path_root), base_path.clone());
+    let snapshot_path = if matches.is_present("snapshot") {
+        Some(snapshot::snapshot_path(&base_path, &root_name))
+    }
+    else { None };
+    let loaded = match snapshot_path {
+        Some(ref path) => match snapshot::load(path, absolute_path_root) {
+            Ok(snapshot) => Some(snapshot),
+            Err(error) => {
+                eprintln!("Can't read the snapshot, build the graph : {}", error);
+                None
+            }
+        },
+        None => None
+    };
+    let (graph, tags_index, root_index) = match loaded {
+        Some(snapshot) => {
+            let since = snapshot.changed_since();
+            let (mut graph, mut tags_index, root_index) = (snapshot.graph, snapshot.tags_index, snapshot.root_index);
+            let report = tag_engine::graph::reconcile(root_index, &mut tags_index, &mut graph,
+                String::from(absolute_path_root), base_path.clone(), since);
+            println!("########## Reconciliation with the snapshot ##########");
+            if report.is_empty() { println!("No changes"); }
+            else { print!("{}", report); }
+            (graph, tags_index, root_index)
+        },
+        None => match tag_engine::graph::make_graph(String::from(absolute_path_root), base_path.clone()) {
+            Ok(graph) => graph,
+            Err(error) => {
+                eprintln!("Can't build the graph : {}", error);
+                exit(1);
+            }
+        }
+    };
     let new_now = Instant::now();
     let elapsed = new_now.duration_since(now);
 
@@ -79,14 +148,27 @@ fn main() {
         write_dot_image(&graph, dot_name, image_name);
     }
 
-    let graph = Arc::new(Mutex::new(graph));
-    let tags_index = Arc::new(Mutex::new(tags_index));
-    let main_graph = Arc::clone(&graph);
-    let main_tags_index = Arc::clone(&tags_index);
+    let save_snapshot = |graph : &MyGraph| {
+        if let Some(ref path) = snapshot_path {
+            match snapshot::save(path, absolute_path_root, graph, root_index) {
+                Ok(()) => (),
+                Err(error) => eprintln!("Can't write the snapshot : {}", error)
+            }
+        }
+    };
+    save_snapshot(&graph);
+    let mut last_save = Instant::now();
+    let mut dirty = false;
+
+    let index = Arc::new(RwLock::new(Index { graph, tags_index, root_index,
+        base_path : base_path.clone() }));
+    let config = Arc::new(RwLock::new(config));
+    let main_index = Arc::clone(&index);
 
     let base_clone = base_path.clone();
+    let socket_path = matches.value_of("socket").unwrap().to_string();
     thread::spawn(move || {
-        tag_engine::server::server(base_clone, &graph, &tags_index);
+        tag_engine::server::server(&socket_path, base_clone, &index, &config, workers);
     });
     
     let (tx, rx) = channel();
@@ -94,22 +176,36 @@ fn main() {
     watcher.watch(absolute_path_root, RecursiveMode::Recursive).expect("watcher watch");
 
     loop {
-        match rx.recv() {
+        match rx.recv_timeout(SNAPSHOT_INTERVAL) {
             Ok(event) => {
                 match event {
-                    Create(_) | Chmod(_) | Remove(_) | Rename(_, _) => {
-                        let mut ref_graph = main_graph.lock().unwrap();
-                        let mut ref_tags_index = main_tags_index.lock().unwrap();
-                        tag_engine::dispatcher(event, &mut ref_tags_index, &mut ref_graph, root_index, base_path.clone());
+                    Create(_) | Chmod(_) | DebouncedEvent::Write(_) | Remove(_) | Rename(_, _) => {
+                        {
+                            // the queries are blocked only during the mutation
+                            let mut index = main_index.write().unwrap();
+                            let Index { ref mut graph, ref mut tags_index, .. } = *index;
+                            // an error concerns a single entry, the next events can still be handled
+                            if let Err(error) = tag_engine::dispatcher(event, tags_index, graph,
+                                root_index, base_path.clone()) {
+                                eprintln!("Can't handle the event : {}", error);
+                            }
+                        }
+                        dirty = true;
                         if debug {
                             println!();
-                            write_dot_image(&ref_graph, dot_name, image_name);
+                            write_dot_image(&main_index.read().unwrap().graph, dot_name, image_name);
                         }
                     }
                     _ => ()
                 }
             },
+            Err(RecvTimeoutError::Timeout) => (),
             Err(e) => println!("watch error: {:?}", e)
         }
+        if dirty && last_save.elapsed() >= SNAPSHOT_INTERVAL {
+            save_snapshot(&main_index.read().unwrap().graph);
+            last_save = Instant::now();
+            dirty = false;
+        }
     }
 }
@@ -1,23 +1,119 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::iter::{Enumerate, Peekable};
+use std::str::Chars;
+
+use regex::Regex;
+
+use petgraph::graph::NodeIndex;
+
+use graph::{Index, NodeKind, Value};
+use planner::Plan;
+use config::Config;
+
 const AND_OPERATOR_STR : &str = "AND";
 const OR_OPERATOR_STR : &str = "OR";
+const NOT_OPERATOR_STR : &str = "NOT";
+const REGEX_PREFIX : &str = "re:";
+const KIND_PREFIX : &str = "is:";
+const UNDER_PREFIX : &str = "under:";
+const EXTENSION_PREFIX : &str = "ext:";
+const PREDICATE_PREFIXES : [&str; 3] = [KIND_PREFIX, UNDER_PREFIX, EXTENSION_PREFIX];
+const INHERIT_SUFFIX : &str = "/**";
+const SIZE_FIELD : &str = "size";
+const MTIME_FIELD : &str = "mtime";
 
 use self::Operator::*;
+/// `NOT` is unary, `ANDNOT` is the binary form written `a AND NOT b`.
 #[derive(Debug, Clone, PartialEq)]
-pub enum Operator { AND, OR }
+pub enum Operator { AND, OR, NOT, ANDNOT }
 impl Operator {
-    fn compare(&self, other : &Operator) -> i8 {
-        match (self, other) {
-            (&AND, &OR) => 1,
-            (&OR, &AND) => -1,
-            _ => 0
+    fn precedence(&self) -> i8 {
+        match *self {
+            NOT => 3,
+            AND | ANDNOT => 2,
+            OR => 1
         }
     }
+
+    fn compare(&self, other : &Operator) -> i8 {
+        self.precedence() - other.precedence()
+    }
+
+    pub fn is_unary(&self) -> bool {
+        *self == NOT
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Arg {
     Operand(String),
-    Operator(Operator)
+    /// operand written `tag/**`
+    Inherited(String),
+    Glob(String),
+    Regex(String),
+    Predicate(Predicate),
+    Operator(Operator),
+    LeftParen,
+    RightParen
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Comparison { Less, LessEqual, Greater, GreaterEqual, Equal, NotEqual }
+impl Comparison {
+    /// Compare `value` to the `reference` given in the query.
+    pub fn test<T : PartialOrd>(&self, value : &T, reference : &T) -> bool {
+        match *self {
+            Comparison::Less => value < reference,
+            Comparison::LessEqual => value <= reference,
+            Comparison::Greater => value > reference,
+            Comparison::GreaterEqual => value >= reference,
+            Comparison::Equal => value == reference,
+            Comparison::NotEqual => value != reference
+        }
+    }
+
+    /// Test the ordering of a value against the reference, values which
+    /// can't be compared are only different.
+    pub fn test_ordering(&self, ordering : Option<Ordering>) -> bool {
+        match ordering {
+            Some(ordering) => self.test(&ordering, &Ordering::Equal),
+            None => *self == Comparison::NotEqual
+        }
+    }
+}
+
+/// Condition on the entries themselves rather than on their tags.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Predicate {
+    Kind(NodeKind),
+    /// Strict descendants of a directory, given by its absolute path or its
+    /// path from the root of the graph.
+    Under(String),
+    /// Size of a file in bytes.
+    Size(Comparison, u64),
+    /// Modification day of a file, in days since the Unix epoch.
+    Mtime(Comparison, i64),
+    Extension(String),
+    /// Comparison on the value of the `key=value` tags with the given key,
+    /// numbers and dates being compared as such and texts literally.
+    Attribute(String, Comparison, Value)
+}
+
+/// Expression tree of a query, built by `parse`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Tag(String),
+    /// tag whose directories pass it on to all their descendants
+    Inherited(String),
+    Glob(String),
+    Regex(String),
+    Predicate(Predicate),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>)
 }
 
 fn str_to_operator(op_str : &str) -> Option<Operator> {
@@ -27,43 +123,462 @@ fn str_to_operator(op_str : &str) -> Option<Operator> {
     else if op_str == OR_OPERATOR_STR {
         Some(OR)
     }
+    else if op_str == NOT_OPERATOR_STR {
+        Some(NOT)
+    }
     else {
         None
     }
 }
 
-pub fn infix_to_postfix(infix : String) -> Vec<Arg> {
-    let infix : Vec<&str> = infix.split(' ').collect();
-    let mut stack = Vec::new();
-    let mut postfix : Vec<Arg> = Vec::new();
-    for arg in infix {
-        if arg == AND_OPERATOR_STR || arg == OR_OPERATOR_STR {
-            let arg = str_to_operator(arg).unwrap();
-            if stack.is_empty() {
-                stack.push(arg);
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseErrorKind {
+    EmptyExpression,
+    MissingOperand,
+    MissingOperator,
+    UnmatchedLeftParen,
+    UnmatchedRightParen,
+    UnterminatedQuote,
+    UnterminatedRegex,
+    InvalidRegex(String),
+    InvalidPredicate(String),
+    /// `/**` after a glob, only a tag can be inherited
+    InheritedGlob(String)
+}
+
+/// Error of a malformed expression, `offset` is the position in characters
+/// of the faulty token in the expression.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub kind : ParseErrorKind,
+    pub offset : usize
+}
+
+impl ParseError {
+    fn new(kind : ParseErrorKind, offset : usize) -> Self {
+        Self { kind, offset }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let message = match self.kind {
+            ParseErrorKind::InvalidRegex(ref error) =>
+                return write!(f, "invalid regex at offset {} : {}", self.offset, error),
+            ParseErrorKind::InvalidPredicate(ref predicate) =>
+                return write!(f, "invalid predicate {:?} at offset {}", predicate, self.offset),
+            ParseErrorKind::InheritedGlob(ref glob) =>
+                return write!(f, "glob {:?} can't be inherited at offset {}", glob, self.offset),
+            ParseErrorKind::EmptyExpression => "empty expression",
+            ParseErrorKind::MissingOperand => "missing operand",
+            ParseErrorKind::MissingOperator => "missing operator",
+            ParseErrorKind::UnmatchedLeftParen => "unmatched '('",
+            ParseErrorKind::UnmatchedRightParen => "unmatched ')'",
+            ParseErrorKind::UnterminatedQuote => "unterminated quote",
+            ParseErrorKind::UnterminatedRegex => "unterminated regex"
+        };
+        write!(f, "{} at offset {}", message, self.offset)
+    }
+}
+
+impl Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub arg : Arg,
+    pub offset : usize
+}
+
+fn is_predicate(word : &str) -> bool {
+    PREDICATE_PREFIXES.iter().any(|prefix| word.starts_with(prefix))
+}
+
+fn str_to_predicate(word : &str, offset : usize) -> Result<Predicate, ParseError> {
+    let invalid = || ParseError::new(ParseErrorKind::InvalidPredicate(word.to_string()), offset);
+    if let Some(kind) = word.strip_prefix(KIND_PREFIX) {
+        match kind {
+            "file" => Ok(Predicate::Kind(NodeKind::File)),
+            "dir" | "directory" => Ok(Predicate::Kind(NodeKind::Directory)),
+            _ => Err(invalid())
+        }
+    }
+    else if let Some(extension) = word.strip_prefix(EXTENSION_PREFIX) {
+        let extension = extension.trim_start_matches('.');
+        if extension.is_empty() { Err(invalid()) } else { Ok(Predicate::Extension(extension.to_string())) }
+    }
+    else {
+        let path = &word[UNDER_PREFIX.len()..];
+        if path.is_empty() { Err(invalid()) } else { Ok(Predicate::Under(path.to_string())) }
+    }
+}
+
+/// Split `key<op>value` in its three parts, `<op>` being one of `<`, `<=`,
+/// `>`, `>=`, `=` and `!=`.
+fn split_comparison(word : &str) -> Option<(&str, Comparison, &str)> {
+    let start = word.find(['<', '>', '=', '!'])?;
+    let (key, rest) = word.split_at(start);
+    let (comparison, length) = if rest.starts_with("<=") { (Comparison::LessEqual, 2) }
+        else if rest.starts_with(">=") { (Comparison::GreaterEqual, 2) }
+        else if rest.starts_with("!=") { (Comparison::NotEqual, 2) }
+        else if rest.starts_with('<') { (Comparison::Less, 1) }
+        else if rest.starts_with('>') { (Comparison::Greater, 1) }
+        else if rest.starts_with('=') { (Comparison::Equal, 1) }
+        else { return None };
+    Some((key, comparison, &rest[length..]))
+}
+
+/// Parse a size in bytes with an optional `K`, `M`, `G` or `T` binary
+/// suffix, like `10M`.
+fn parse_size(value : &str) -> Option<u64> {
+    let digits = value.find(|c : char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(digits);
+    let number : u64 = number.parse().ok()?;
+    let unit = unit.trim_end_matches(['B', 'b']);
+    let shift = match unit.to_uppercase().as_str() {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return None
+    };
+    number.checked_mul(1 << shift)
+}
+
+/// Parse a `YYYY-MM-DD` date to its number of days since the Unix epoch.
+pub fn parse_date(value : &str) -> Option<i64> {
+    let parts : Vec<&str> = value.split('-').collect();
+    if parts.len() != 3 || parts[0].len() != 4 {
+        return None;
+    }
+    let year : i64 = parts[0].parse().ok()?;
+    let month : i64 = parts[1].parse().ok()?;
+    let day : i64 = parts[2].parse().ok()?;
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = match month {
+        2 => if leap { 29 } else { 28 },
+        4 | 6 | 9 | 11 => 30,
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        _ => return None
+    };
+    if day < 1 || day > month_days {
+        return None;
+    }
+    // days from civil, the year starting in march
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some(era * 146_097 + day_of_era - 719_468)
+}
+
+/// `size` and `mtime` comparisons if the value is a size or a date, else
+/// comparison on the value of `key=value` tags, which `size` and `mtime` only
+/// allow for `=` and `!=`. `None` if the word isn't a comparison.
+fn str_to_comparison(word : &str, offset : usize) -> Option<Result<Predicate, ParseError>> {
+    let (key, comparison, value) = split_comparison(word)?;
+    let size = if key == SIZE_FIELD { parse_size(value) } else { None };
+    let day = if key == MTIME_FIELD { parse_date(value) } else { None };
+    let is_ordering = comparison != Comparison::Equal && comparison != Comparison::NotEqual;
+    let predicate = if key.is_empty() {
+        return None;
+    }
+    else if let Some(size) = size {
+        Some(Predicate::Size(comparison, size))
+    }
+    else if let Some(day) = day {
+        Some(Predicate::Mtime(comparison, day))
+    }
+    else if value.is_empty() || (is_ordering && (key == SIZE_FIELD || key == MTIME_FIELD)) {
+        None
+    }
+    else { Some(Predicate::Attribute(key.to_string(), comparison, Value::new(value))) };
+    Some(predicate.ok_or_else(|| ParseError::new(ParseErrorKind::InvalidPredicate(word.to_string()), offset)))
+}
+
+fn push_word(word : &mut String, offset : usize, tokens : &mut Vec<Token>) -> Result<(), ParseError> {
+    if !word.is_empty() {
+        let arg = if let Some(op) = str_to_operator(word) {
+            Arg::Operator(op)
+        }
+        else if is_predicate(word) {
+            Arg::Predicate(str_to_predicate(word, offset)?)
+        }
+        else if let Some(predicate) = str_to_comparison(word, offset) {
+            Arg::Predicate(predicate?)
+        }
+        else if let Some(tag) = word.strip_suffix(INHERIT_SUFFIX).filter(|tag| !tag.is_empty()) {
+            if tag.contains(['*', '?']) {
+                return Err(ParseError::new(ParseErrorKind::InheritedGlob(word.clone()), offset));
+            }
+            Arg::Inherited(tag.to_string())
+        }
+        else if word.contains(['*', '?']) {
+            Arg::Glob(word.clone())
+        }
+        else {
+            Arg::Operand(word.clone())
+        };
+        tokens.push(Token { arg, offset });
+        word.clear();
+    }
+    Ok(())
+}
+
+/// Match `text` against a glob `pattern`, where `*` matches any sequence
+/// of characters and `?` matches exactly one character.
+pub fn glob_match(pattern : &str, text : &str) -> bool {
+    let pattern : Vec<char> = pattern.chars().collect();
+    let text : Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // position of the last `*` in pattern and of the text it started to match
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        }
+        else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        }
+        else {
+            match backtrack {
+                Some((star, start)) => {
+                    p = star + 1;
+                    t = start + 1;
+                    backtrack = Some((star, start + 1));
+                },
+                None => return false
+            }
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Read a quoted string up to its closing `"`, `offset` is the one of the
+/// opening `"`.
+fn read_quoted(chars : &mut Peekable<Enumerate<Chars>>, offset : usize) -> Result<String, ParseError> {
+    let mut value = String::new();
+    loop {
+        match chars.next() {
+            Some((_, '"')) => return Ok(value),
+            Some((_, '\\')) => match chars.next() {
+                Some((_, escaped)) => value.push(escaped),
+                None => return Err(ParseError::new(ParseErrorKind::UnterminatedQuote, offset))
+            },
+            Some((_, c)) => value.push(c),
+            None => return Err(ParseError::new(ParseErrorKind::UnterminatedQuote, offset))
+        }
+    }
+}
+
+/// Read a regex up to its closing `/`, `offset` is the one of the `re:`
+/// prefix. `\/` stands for a `/` in the regex.
+fn read_regex(chars : &mut Peekable<Enumerate<Chars>>, offset : usize) -> Result<String, ParseError> {
+    let mut pattern = String::new();
+    loop {
+        match chars.next() {
+            Some((_, '/')) => break,
+            Some((_, '\\')) => match chars.next() {
+                Some((_, '/')) => pattern.push('/'),
+                Some((_, c)) => {
+                    pattern.push('\\');
+                    pattern.push(c);
+                },
+                None => return Err(ParseError::new(ParseErrorKind::UnterminatedRegex, offset))
+            },
+            Some((_, c)) => pattern.push(c),
+            None => return Err(ParseError::new(ParseErrorKind::UnterminatedRegex, offset))
+        }
+    }
+    match Regex::new(&pattern) {
+        Ok(_) => Ok(pattern),
+        Err(error) => Err(ParseError::new(ParseErrorKind::InvalidRegex(error.to_string()), offset))
+    }
+}
+
+/// Split an infix expression in operands, operators and parentheses.
+/// Tokens are separated by any whitespace, parentheses don't need to be
+/// surrounded by spaces. A word containing `*` or `?` is a glob and
+/// `re:/.../` is a regex, which may contain spaces or parentheses.
+/// `is:file` and `is:dir` select entries by kind, `under:path` by location,
+/// its path can be quoted. `size>10M`, `mtime<2018-06-01` and `ext:pdf`
+/// select files by metadata, other comparisons like `priority>=2` select
+/// entries by their `key=value` tags. `tag/**` passes the tag of a
+/// directory on to its descendants, which globs can't. A double-quoted tag is always a plain
+/// operand, even `"AND"` or `"a*"`, and `\` escapes the next character
+/// inside quotes.
+pub fn tokenize(infix : &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut word_offset = 0;
+    let mut chars = infix.chars().enumerate().peekable();
+    while let Some((offset, c)) = chars.next() {
+        match c {
+            '(' | ')' => {
+                push_word(&mut word, word_offset, &mut tokens)?;
+                let arg = if c == '(' { Arg::LeftParen } else { Arg::RightParen };
+                tokens.push(Token { arg, offset });
+            },
+            // quoted value of a predicate, like `under:"/data/Q3 report"`
+            '"' if PREDICATE_PREFIXES.contains(&word.as_str()) => {
+                let value = read_quoted(&mut chars, offset)?;
+                word.push_str(&value);
+                push_word(&mut word, word_offset, &mut tokens)?;
+            },
+            '"' => {
+                push_word(&mut word, word_offset, &mut tokens)?;
+                let tag = read_quoted(&mut chars, offset)?;
+                tokens.push(Token { arg : Arg::Operand(tag), offset });
+            },
+            _ if c.is_whitespace() => push_word(&mut word, word_offset, &mut tokens)?,
+            _ => {
+                if word.is_empty() {
+                    word_offset = offset;
+                }
+                word.push(c);
+                if word == REGEX_PREFIX && chars.peek().map(|&(_, c)| c) == Some('/') {
+                    chars.next();
+                    let pattern = read_regex(&mut chars, word_offset)?;
+                    tokens.push(Token { arg : Arg::Regex(pattern), offset : word_offset });
+                    word.clear();
+                }
             }
-            else {
-                while !stack.is_empty() {
-                    let mut top_stack = stack.get(stack.len() - 1).unwrap().clone();
-                    let mut compare = arg.compare(&top_stack);
-                    if compare > 0 {
-                        break;
+        }
+    }
+    push_word(&mut word, word_offset, &mut tokens)?;
+    Ok(tokens)
+}
+
+pub fn infix_to_postfix(infix : String) -> Result<Vec<Arg>, ParseError> {
+    let end = infix.chars().count();
+    let mut infix = tokenize(&infix)?.into_iter().peekable();
+    if infix.peek().is_none() {
+        return Err(ParseError::new(ParseErrorKind::EmptyExpression, 0));
+    }
+    // operators and parentheses, with their offset
+    let mut stack : Vec<(Arg, usize)> = Vec::new();
+    let mut postfix : Vec<Arg> = Vec::new();
+    let mut expect_operand = true;
+    while let Some(Token { arg, offset }) = infix.next() {
+        match arg {
+            Arg::Operator(mut op) => {
+                if op == AND && infix.peek().map(|t| &t.arg) == Some(&Arg::Operator(NOT)) {
+                    infix.next();
+                    op = ANDNOT;
+                }
+                // a prefix operator has no left operand to reduce
+                if op.is_unary() {
+                    if !expect_operand {
+                        return Err(ParseError::new(ParseErrorKind::MissingOperator, offset));
+                    }
+                }
+                else {
+                    if expect_operand {
+                        return Err(ParseError::new(ParseErrorKind::MissingOperand, offset));
                     }
-                    else {
-                        postfix.push(Arg::Operator(stack.pop().unwrap()));
+                    while let Some((Arg::Operator(top_stack), _)) = stack.last().cloned() {
+                        if op.compare(&top_stack) > 0 {
+                            break;
+                        }
+                        postfix.push(stack.pop().unwrap().0);
                     }
+                    expect_operand = true;
                 }
-                stack.push(arg);
+                stack.push((Arg::Operator(op), offset));
+            },
+            Arg::LeftParen => {
+                if !expect_operand {
+                    return Err(ParseError::new(ParseErrorKind::MissingOperator, offset));
+                }
+                stack.push((Arg::LeftParen, offset));
+            },
+            Arg::RightParen => {
+                if expect_operand {
+                    return Err(ParseError::new(ParseErrorKind::MissingOperand, offset));
+                }
+                loop {
+                    match stack.pop() {
+                        Some((Arg::LeftParen, _)) => break,
+                        Some((top_stack, _)) => postfix.push(top_stack),
+                        None => return Err(ParseError::new(ParseErrorKind::UnmatchedRightParen, offset))
+                    }
+                }
+            },
+            Arg::Operand(_) | Arg::Inherited(_) | Arg::Glob(_) | Arg::Regex(_) | Arg::Predicate(_) => {
+                if !expect_operand {
+                    return Err(ParseError::new(ParseErrorKind::MissingOperator, offset));
+                }
+                postfix.push(arg);
+                expect_operand = false;
             }
         }
-        else {
-            postfix.push(Arg::Operand(arg.to_string()));
+    }
+    if expect_operand {
+        return Err(ParseError::new(ParseErrorKind::MissingOperand, end));
+    }
+    for (arg, offset) in stack.into_iter().rev() {
+        if arg == Arg::LeftParen {
+            return Err(ParseError::new(ParseErrorKind::UnmatchedLeftParen, offset));
         }
+        postfix.push(arg);
     }
-    for op in stack.into_iter().rev() {
-        postfix.push(Arg::Operator(op));
+    Ok(postfix)
+}
+
+/// Parse an infix expression to its expression tree. `a AND NOT b` gives
+/// `And(a, Not(b))`.
+pub fn parse(infix : &str) -> Result<Expr, ParseError> {
+    let postfix = infix_to_postfix(infix.to_string())?;
+    let mut stack = Vec::new();
+    // the postfix expression is well formed, operands are always on the stack
+    for arg in postfix {
+        let expr = match arg {
+            Arg::Operand(tag) => Expr::Tag(tag),
+            Arg::Inherited(tag) => Expr::Inherited(tag),
+            Arg::Glob(pattern) => Expr::Glob(pattern),
+            Arg::Regex(pattern) => Expr::Regex(pattern),
+            Arg::Predicate(predicate) => Expr::Predicate(predicate),
+            Arg::Operator(NOT) => Expr::Not(Box::new(stack.pop().unwrap())),
+            Arg::Operator(op) => {
+                let right = Box::new(stack.pop().unwrap());
+                let left = Box::new(stack.pop().unwrap());
+                match op {
+                    AND => Expr::And(left, right),
+                    OR => Expr::Or(left, right),
+                    ANDNOT => Expr::And(left, Box::new(Expr::Not(right))),
+                    NOT => unreachable!()
+                }
+            },
+            Arg::LeftParen | Arg::RightParen => unreachable!()
+        };
+        stack.push(expr);
     }
-    postfix
+    Ok(stack.pop().unwrap())
+}
+
+/// Evaluate an expression to the set of files and directories matching it,
+/// the entry point to embed queries in another tool :
+///
+/// ```no_run
+/// use tag_engine::graph::{Index, make_graph};
+/// use tag_engine::parse::{parse, evaluate};
+/// use tag_engine::config::Config;
+///
+/// let base_path = String::from("/data/");
+/// let (graph, tags_index, root_index) = make_graph(String::from("/data/finance"), base_path.clone()).unwrap();
+/// let index = Index { graph, tags_index, root_index, base_path };
+/// let entries = evaluate(&parse("invoice AND year>=2018").unwrap(), &index, &Config::default());
+/// ```
+///
+/// A tag also matches its aliases and the tags implying it from `config`,
+/// and the descendants of the directories it tags if it is `Inherited` or in
+/// inheritance mode. `Not` is the complement against all the entries of the
+/// graph, an invalid `Regex` matches nothing.
+pub fn evaluate(expr : &Expr, index : &Index, config : &Config) -> HashSet<NodeIndex> {
+    Plan::new(expr, index, config).execute(&index.graph)
 }
 
 #[cfg(test)]
@@ -78,7 +593,7 @@ mod tests {
             Arg::Operand(String::from("fred")),
             Arg::Operator(Operator::AND)
         ];
-        assert_eq!(infix_to_postfix(infix), postfix);
+        assert_eq!(infix_to_postfix(infix), Ok(postfix));
     }
 
     #[test]
@@ -89,7 +604,7 @@ mod tests {
             Arg::Operand(String::from("fred")),
             Arg::Operator(Operator::OR)
         ];
-        assert_eq!(infix_to_postfix(infix), postfix);
+        assert_eq!(infix_to_postfix(infix), Ok(postfix));
     }
 
     #[test]
@@ -102,7 +617,7 @@ mod tests {
             Arg::Operand(String::from("max")),
             Arg::Operator(Operator::OR)
         ];
-        assert_eq!(infix_to_postfix(infix), postfix);
+        assert_eq!(infix_to_postfix(infix), Ok(postfix));
     }
 
     #[test]
@@ -115,7 +630,7 @@ mod tests {
             Arg::Operator(Operator::AND),
             Arg::Operator(Operator::OR)
         ];
-        assert_eq!(infix_to_postfix(infix), postfix);
+        assert_eq!(infix_to_postfix(infix), Ok(postfix));
     }
 
     #[test]
@@ -128,7 +643,7 @@ mod tests {
             Arg::Operand(String::from("max")),
             Arg::Operator(Operator::AND)
         ];
-        assert_eq!(infix_to_postfix(infix), postfix);
+        assert_eq!(infix_to_postfix(infix), Ok(postfix));
     }
 
     #[test]
@@ -143,6 +658,284 @@ mod tests {
             Arg::Operator(Operator::AND),
             Arg::Operator(Operator::OR)
         ];
-        assert_eq!(infix_to_postfix(infix), postfix);
+        assert_eq!(infix_to_postfix(infix), Ok(postfix));
+    }
+
+    #[test]
+    fn test_infix_to_postfix_not_1() {
+        let infix = String::from("NOT bob");
+        let postfix = vec![
+            Arg::Operand(String::from("bob")),
+            Arg::Operator(Operator::NOT)
+        ];
+        assert_eq!(infix_to_postfix(infix), Ok(postfix));
+    }
+
+    #[test]
+    fn test_infix_to_postfix_not_2() {
+        let infix = String::from("bob AND NOT fred OR max");
+        let postfix = vec![
+            Arg::Operand(String::from("bob")),
+            Arg::Operand(String::from("fred")),
+            Arg::Operator(Operator::ANDNOT),
+            Arg::Operand(String::from("max")),
+            Arg::Operator(Operator::OR)
+        ];
+        assert_eq!(infix_to_postfix(infix), Ok(postfix));
+    }
+
+    #[test]
+    fn test_infix_to_postfix_not_3() {
+        let infix = String::from("NOT bob AND fred OR NOT max");
+        let postfix = vec![
+            Arg::Operand(String::from("bob")),
+            Arg::Operator(Operator::NOT),
+            Arg::Operand(String::from("fred")),
+            Arg::Operator(Operator::AND),
+            Arg::Operand(String::from("max")),
+            Arg::Operator(Operator::NOT),
+            Arg::Operator(Operator::OR)
+        ];
+        assert_eq!(infix_to_postfix(infix), Ok(postfix));
+    }
+
+    #[test]
+    fn test_infix_to_postfix_parens_1() {
+        let infix = String::from("(bob OR fred) AND max");
+        let postfix = vec![
+            Arg::Operand(String::from("bob")),
+            Arg::Operand(String::from("fred")),
+            Arg::Operator(Operator::OR),
+            Arg::Operand(String::from("max")),
+            Arg::Operator(Operator::AND)
+        ];
+        assert_eq!(infix_to_postfix(infix), Ok(postfix));
+    }
+
+    #[test]
+    fn test_infix_to_postfix_parens_2() {
+        let infix = String::from("bob AND ( fred OR max )");
+        let postfix = vec![
+            Arg::Operand(String::from("bob")),
+            Arg::Operand(String::from("fred")),
+            Arg::Operand(String::from("max")),
+            Arg::Operator(Operator::OR),
+            Arg::Operator(Operator::AND)
+        ];
+        assert_eq!(infix_to_postfix(infix), Ok(postfix));
+    }
+
+    #[test]
+    fn test_infix_to_postfix_parens_3() {
+        let infix = String::from("NOT (bob OR (fred AND NOT max))");
+        let postfix = vec![
+            Arg::Operand(String::from("bob")),
+            Arg::Operand(String::from("fred")),
+            Arg::Operand(String::from("max")),
+            Arg::Operator(Operator::ANDNOT),
+            Arg::Operator(Operator::OR),
+            Arg::Operator(Operator::NOT)
+        ];
+        assert_eq!(infix_to_postfix(infix), Ok(postfix));
+    }
+
+    #[test]
+    fn test_infix_to_postfix_errors() {
+        let error = |kind, offset| Err(ParseError::new(kind, offset));
+        assert_eq!(infix_to_postfix(String::from("")), error(ParseErrorKind::EmptyExpression, 0));
+        assert_eq!(infix_to_postfix(String::from("AND bob")), error(ParseErrorKind::MissingOperand, 0));
+        assert_eq!(infix_to_postfix(String::from("bob OR")), error(ParseErrorKind::MissingOperand, 6));
+        assert_eq!(infix_to_postfix(String::from("bob fred")), error(ParseErrorKind::MissingOperator, 4));
+        assert_eq!(infix_to_postfix(String::from("bob AND ()")), error(ParseErrorKind::MissingOperand, 9));
+        assert_eq!(infix_to_postfix(String::from("(bob AND fred")), error(ParseErrorKind::UnmatchedLeftParen, 0));
+        assert_eq!(infix_to_postfix(String::from("bob) OR fred")), error(ParseErrorKind::UnmatchedRightParen, 3));
+    }
+
+    #[test]
+    fn test_infix_to_postfix_quotes() {
+        let infix = String::from("\t\"Q3 report\"  AND (\"AND\" OR \"say \\\"hi\\\" \\\\o/\")");
+        let postfix = vec![
+            Arg::Operand(String::from("Q3 report")),
+            Arg::Operand(String::from("AND")),
+            Arg::Operand(String::from("say \"hi\" \\o/")),
+            Arg::Operator(Operator::OR),
+            Arg::Operator(Operator::AND)
+        ];
+        assert_eq!(infix_to_postfix(infix), Ok(postfix));
+        assert_eq!(infix_to_postfix(String::from("bob AND \"to do")),
+            Err(ParseError::new(ParseErrorKind::UnterminatedQuote, 8)));
+    }
+
+    #[test]
+    fn test_infix_to_postfix_glob() {
+        let infix = String::from("proj-* AND NOT \"a*\" OR *2018?");
+        let postfix = vec![
+            Arg::Glob(String::from("proj-*")),
+            Arg::Operand(String::from("a*")),
+            Arg::Operator(Operator::ANDNOT),
+            Arg::Glob(String::from("*2018?")),
+            Arg::Operator(Operator::OR)
+        ];
+        assert_eq!(infix_to_postfix(infix), Ok(postfix));
+    }
+
+    #[test]
+    fn test_infix_to_postfix_inherited() {
+        let infix = String::from("projectX/** AND lang/rust OR /**");
+        let postfix = vec![
+            Arg::Inherited(String::from("projectX")),
+            Arg::Operand(String::from("lang/rust")),
+            Arg::Operator(Operator::AND),
+            Arg::Glob(String::from("/**")),
+            Arg::Operator(Operator::OR)
+        ];
+        assert_eq!(infix_to_postfix(infix), Ok(postfix));
+        assert_eq!(infix_to_postfix(String::from("a OR p*/**")),
+            Err(ParseError::new(ParseErrorKind::InheritedGlob(String::from("p*/**")), 5)));
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("proj-*", "proj-"));
+        assert!(glob_match("proj-*", "proj-tag_engine"));
+        assert!(!glob_match("proj-*", "project"));
+        assert!(glob_match("*2018*", "report-2018-q3"));
+        assert!(glob_match("*2018*", "2018"));
+        assert!(!glob_match("*2018*", "201-8"));
+        assert!(glob_match("v?.*.?", "v1.12.3"));
+        assert!(!glob_match("v?", "v10"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("a*b*c", "aXbYbZ"));
+    }
+
+    #[test]
+    fn test_infix_to_postfix_regex() {
+        let infix = String::from("re:/^client-[0-9]+$/ OR (re:/a\\/b (c)/ AND re:)");
+        let postfix = vec![
+            Arg::Regex(String::from("^client-[0-9]+$")),
+            Arg::Regex(String::from("a/b (c)")),
+            Arg::Operand(String::from("re:")),
+            Arg::Operator(Operator::AND),
+            Arg::Operator(Operator::OR)
+        ];
+        assert_eq!(infix_to_postfix(infix), Ok(postfix));
+        assert_eq!(infix_to_postfix(String::from("bob OR re:/^client")),
+            Err(ParseError::new(ParseErrorKind::UnterminatedRegex, 7)));
+        match infix_to_postfix(String::from("bob OR re:/client-[0-9/")) {
+            Err(ParseError { kind : ParseErrorKind::InvalidRegex(_), offset : 7 }) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let tag = |name : &str| Box::new(Expr::Tag(name.to_string()));
+        let expr = Expr::Or(
+            Box::new(Expr::And(tag("bob"), Box::new(Expr::Not(tag("fred"))))),
+            Box::new(Expr::Not(Box::new(Expr::And(Box::new(Expr::Glob(String::from("max*"))), tag("paul")))))
+        );
+        assert_eq!(parse("bob AND NOT fred OR NOT (max* AND paul)"), Ok(expr));
+        assert_eq!(parse("bob AND"), Err(ParseError::new(ParseErrorKind::MissingOperand, 7)));
+        assert_eq!(parse("is:dir OR is:file"), Ok(Expr::Or(
+            Box::new(Expr::Predicate(Predicate::Kind(NodeKind::Directory))),
+            Box::new(Expr::Predicate(Predicate::Kind(NodeKind::File))))));
+        assert_eq!(parse("under:\"/data/Q3 report\""),
+            Ok(Expr::Predicate(Predicate::Under(String::from("/data/Q3 report")))));
+        assert_eq!(parse("size>10M AND mtime!=1970-01-02 AND ext:.pdf"), Ok(Expr::And(
+            Box::new(Expr::And(
+                Box::new(Expr::Predicate(Predicate::Size(Comparison::Greater, 10 << 20))),
+                Box::new(Expr::Predicate(Predicate::Mtime(Comparison::NotEqual, 1))))),
+            Box::new(Expr::Predicate(Predicate::Extension(String::from("pdf")))))));
+        let attribute = |key : &str, comparison, value| Ok(Expr::Predicate(Predicate::Attribute(key.to_string(),
+            comparison, value)));
+        assert_eq!(parse("size=big"), attribute("size", Comparison::Equal, Value::Text(String::from("big"))));
+        assert_eq!(parse("mtime!=2018-02-30"),
+            attribute("mtime", Comparison::NotEqual, Value::Text(String::from("2018-02-30"))));
+        for (infix, offset) in [("mtime<2018-02-30", 0), ("a OR size>1.5G", 5), ("size<=10KiB", 0)] {
+            assert_eq!(parse(infix), Err(ParseError::new(ParseErrorKind::InvalidPredicate(infix[offset..]
+                .to_string()), offset)));
+        }
+        assert_eq!(parse("version=1.10"), attribute("version", Comparison::Equal, Value::Number(1.1)));
+        assert_eq!(parse("x>nan"), attribute("x", Comparison::Greater, Value::Text(String::from("nan"))));
+        assert_eq!(parse("priority>=2 OR client=acme"), Ok(Expr::Or(
+            Box::new(Expr::Predicate(Predicate::Attribute(String::from("priority"),
+                Comparison::GreaterEqual, Value::Number(2.0)))),
+            Box::new(Expr::Predicate(Predicate::Attribute(String::from("client"),
+                Comparison::Equal, Value::Text(String::from("acme"))))))));
+        assert_eq!(parse("bob AND is:link"),
+            Err(ParseError::new(ParseErrorKind::InvalidPredicate(String::from("is:link")), 8)));
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(parse_date("1970-01-01"), Some(0));
+        assert_eq!(parse_date("2000-03-01"), Some(11_017));
+        assert_eq!(parse_date("2018-06-01"), Some(17_683));
+        assert_eq!(parse_date("1969-12-31"), Some(-1));
+        assert_eq!(parse_date("2018-13-01"), None);
+        assert_eq!(parse_date("18-06-01"), None);
+    }
+
+    #[test]
+    fn test_evaluate() {
+        use graph::{FileMeta, find_node_index, test_index};
+        let mut index = test_index(&[
+            ("root/a.pdf", &["photo", "priority=1", "version=1.10", "lang/rust"]),
+            ("root/b.txt", &["photo", "private", "priority=3.0", "lang"]),
+            ("root/dir/", &["photo", "priority=3.0"]),
+            ("root/dir/c", &["due=2018-06-01", "client=acme", "size=big"])]);
+        let root = index.root_index;
+        let mut file = |path : &str, size, date| {
+            let entry_index = find_node_index(root, &index.graph, path).unwrap();
+            let node = index.graph.node_weight_mut(entry_index).unwrap();
+            let extension = node.name.split('.').nth(1).map(|ext| ext.to_string());
+            node.meta = Some(FileMeta { size, mtime : parse_date(date).unwrap() * 86_400 + 3600, extension });
+            entry_index
+        };
+        let a = file("root/a.pdf", 2048, "2018-05-01");
+        let b = file("root/b.txt", 20 << 20, "2018-06-01");
+        let c = file("root/dir/c", 0, "2018-07-01");
+        let dir = find_node_index(root, &index.graph, "root/dir").unwrap();
+
+        let config = Config::default();
+        let query = |infix| evaluate(&parse(infix).unwrap(), &index, &config);
+        let set = |entries : &[NodeIndex]| entries.iter().cloned().collect::<HashSet<_>>();
+        assert_eq!(query("photo AND NOT private"), set(&[a, dir]));
+        assert_eq!(query("NOT photo"), set(&[root, c]));
+        assert_eq!(query("re:/^pri/ OR ph*"), set(&[a, b, dir]));
+        assert_eq!(query("photo AND is:file"), set(&[a, b]));
+        assert_eq!(query("is:dir AND NOT photo"), set(&[root]));
+        assert_eq!(query("photo AND under:/data/root"), set(&[a, b, dir]));
+        assert_eq!(query("under:root/dir/ OR (private AND under:\"/data/root/dir\")"), set(&[c]));
+        assert_eq!(query("NOT under:/data/root"), set(&[root]));
+        assert_eq!(query("under:/data/root/unknown OR under:/elsewhere/root"), set(&[]));
+        assert_eq!(query("size>=2K AND size<10M"), set(&[a]));
+        assert_eq!(query("mtime=2018-06-01 OR ext:PDF"), set(&[a, b]));
+        assert_eq!(query("mtime>2018-06-01 OR (mtime<=2018-06-01 AND NOT photo)"), set(&[c]));
+        assert_eq!(query("NOT size>0"), set(&[root, dir, c]));
+        assert_eq!(query("priority>=2"), set(&[b, dir]));
+        assert_eq!(query("priority=3.0 AND is:file"), set(&[b]));
+        assert_eq!(query("priority=3 OR version=1.1"), set(&[a, b, dir]));
+        assert_eq!(query("priority!=3 AND NOT version=1.10"), set(&[]));
+        assert_eq!(query("version=1.10 OR size=big"), set(&[a, c]));
+        assert_eq!(query("version!=1.1 OR size!=big"), set(&[]));
+        assert_eq!(query("due<2018-06-02 AND client=acme"), set(&[c]));
+        assert_eq!(query("client!=acme OR due>1"), set(&[]));
+        assert_eq!(query("\"client=acme\" AND NOT priority<2"), set(&[c]));
+        assert_eq!(query("lang"), set(&[a, b]));
+        assert_eq!(query("lang/rust OR NOT lang"), set(&[root, dir, a, c]));
+        assert_eq!(query("photo AND unknown"), set(&[]));
+        assert_eq!(query("NOT private AND NOT photo"), set(&[root, c]));
+        assert_eq!(query("NOT private AND (a* OR photo) AND NOT unknown"), set(&[a, dir]));
+        assert_eq!(query("photo/** AND NOT photo"), set(&[c]));
+        assert_eq!(query("priority>2 AND NOT private/**"), set(&[dir]));
+        assert_eq!(query("NOT photo/**"), set(&[root]));
+
+        let mut config = Config::default();
+        config.set_inherit(true);
+        let query = |infix| evaluate(&parse(infix).unwrap(), &index, &config);
+        assert_eq!(query("photo AND is:file"), set(&[a, b, c]));
+        assert_eq!(query("priority>=2 AND due=2018-06-01"), set(&[c]));
+        assert_eq!(query("private OR NOT ph*"), set(&[root, b]));
     }
 }
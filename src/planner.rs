@@ -0,0 +1,442 @@
+#[cfg(test)]
+use std::cell::Cell;
+use std::collections::HashSet;
+
+use regex::Regex;
+
+use petgraph::graph::NodeIndex;
+use petgraph::Direction;
+
+use graph::{MyGraph, Index, Attribute, NodeKind, Value, find_node_index, get_parent_index, tagged_entries, tag_descendants};
+use parse::{Expr, Predicate, Comparison, glob_match};
+use config::Config;
+
+const SECONDS_PER_DAY : i64 = 86_400;
+
+#[cfg(test)]
+thread_local! {
+    /// entries checked by `Plan::contains` on this thread
+    static PROBES : Cell<usize> = Cell::new(0);
+}
+
+/// Evaluation plan of an expression. Operands are resolved to their tag
+/// nodes, including their aliases, the tags implying them and their
+/// descendant tags, and `And`/`Or` chains are flattened, their children
+/// sorted by estimated cardinality.
+#[derive(Debug)]
+pub enum Plan {
+    Tags(HashSet<NodeIndex>, usize),
+    /// tags applying to their entries and to all the descendants of their
+    /// directories, the cost only counts the tagged entries
+    Inherited(HashSet<NodeIndex>, usize),
+    And(Vec<Plan>, usize),
+    Or(Vec<Plan>, usize),
+    Predicate(EntryPredicate, usize),
+    /// `Predicate::Under` resolved to its directory, if it exists
+    Under(Option<NodeIndex>, usize),
+    Not(Box<Plan>, usize)
+}
+
+/// `Predicate` checked on each entry, `under:` and the `key=value`
+/// comparisons being resolved to a directory and to tags by the planner.
+#[derive(Debug)]
+pub enum EntryPredicate {
+    Kind(NodeKind),
+    Size(Comparison, u64),
+    Mtime(Comparison, i64),
+    Extension(String)
+}
+
+/// Plan of the given tags and all their descendant tags.
+fn descendants_plan<I>(graph : &MyGraph, tags_indexes : I, inherit : bool) -> Plan
+    where I : IntoIterator<Item = NodeIndex> {
+    let mut tags = HashSet::new();
+    for tag_index in tags_indexes {
+        if !tags.contains(&tag_index) {
+            tag_descendants(graph, tag_index, &mut tags);
+        }
+    }
+    let cost = tags.iter().map(|tag_index| tagged_entries(graph, *tag_index).count()).sum();
+    if inherit { Plan::Inherited(tags, cost) }
+    else { Plan::Tags(tags, cost) }
+}
+
+/// Plan of the tags matching `is_match`, among the tags of the graph and the
+/// tags of the configuration, with their aliases and the tags implying them.
+fn tags_plan<F>(index : &Index, config : &Config, is_match : F) -> Plan
+    where F : Fn(&str, Option<&Attribute>) -> bool {
+    let (graph, tags_index) = (&index.graph, &index.tags_index);
+    let mut matched : HashSet<&str> = tags_index.values()
+        .map(|tag_index| graph.node_weight(*tag_index).unwrap())
+        .filter(|tag| is_match(&tag.name, tag.attribute.as_ref()))
+        .map(|tag| tag.name.as_str()).collect();
+    matched.extend(config.tags().into_iter()
+        .filter(|tag| !tags_index.contains_key(*tag) && is_match(tag, Attribute::new(tag).as_ref())));
+    descendants_plan(graph, matched.into_iter().flat_map(|tag| config.matching_tags(tag))
+        .filter_map(|synonym| tags_index.get(synonym).cloned()), config.inherit())
+}
+
+fn is_tagged(graph : &MyGraph, tags : &HashSet<NodeIndex>, entry : NodeIndex) -> bool {
+    graph.neighbors_directed(entry, Direction::Incoming).any(|neighbor| tags.contains(&neighbor))
+}
+
+fn flatten<'a>(expr : &'a Expr, and : bool, operands : &mut Vec<&'a Expr>) {
+    match (expr, and) {
+        (&Expr::And(ref left, ref right), true) | (&Expr::Or(ref left, ref right), false) => {
+            flatten(left, and, operands);
+            flatten(right, and, operands);
+        },
+        _ => operands.push(expr)
+    }
+}
+
+fn all_entries(graph : &MyGraph) -> HashSet<NodeIndex> {
+    graph.node_indices().filter(|index| {
+        match graph.node_weight(*index).unwrap().kind {
+            NodeKind::File | NodeKind::Directory => true,
+            NodeKind::Tag => false
+        }
+    }).collect()
+}
+
+/// Resolve an absolute path, or a path starting at the root, to its node.
+fn resolve_path(graph : &MyGraph, root_index : NodeIndex, base_path : &str, path : &str)
+    -> Option<NodeIndex> {
+    let path = path.trim_end_matches('/');
+    let local = if path.starts_with('/') {
+        if !path.starts_with(base_path) {
+            return None;
+        }
+        &path[base_path.len()..]
+    }
+    else { path };
+    find_node_index(root_index, graph, local)
+}
+
+fn is_under(graph : &MyGraph, directory : NodeIndex, entry : NodeIndex) -> bool {
+    let mut current = entry;
+    while let Some(parent) = get_parent_index(graph, current) {
+        if parent == directory {
+            return true;
+        }
+        current = parent;
+    }
+    false
+}
+
+fn descendants(graph : &MyGraph, directory : NodeIndex, entries : &mut HashSet<NodeIndex>) {
+    for neighbor in graph.neighbors_directed(directory, Direction::Outgoing) {
+        match graph.node_weight(neighbor).unwrap().kind {
+            NodeKind::File => { entries.insert(neighbor); },
+            NodeKind::Directory => {
+                entries.insert(neighbor);
+                descendants(graph, neighbor, entries);
+            },
+            NodeKind::Tag => ()
+        }
+    }
+}
+
+fn matches(predicate : &EntryPredicate, graph : &MyGraph, entry : NodeIndex) -> bool {
+    let node = graph.node_weight(entry).unwrap();
+    match *predicate {
+        EntryPredicate::Kind(ref kind) => node.kind == *kind,
+        EntryPredicate::Size(ref comparison, size) =>
+            node.meta.as_ref().is_some_and(|meta| comparison.test(&meta.size, &size)),
+        EntryPredicate::Mtime(ref comparison, day) => node.meta.as_ref()
+            .is_some_and(|meta| comparison.test(&meta.mtime.div_euclid(SECONDS_PER_DAY), &day)),
+        EntryPredicate::Extension(ref extension) => node.meta.as_ref()
+            .and_then(|meta| meta.extension.as_ref())
+            .is_some_and(|ext| ext.eq_ignore_ascii_case(extension))
+    }
+}
+
+/// Operand of an intersection whose entries are collected, the first one
+/// which isn't a complement, a complement being only collected if all the
+/// operands are complements.
+fn driver(children : &[Plan]) -> usize {
+    children.iter().position(|child| !matches!(*child, Plan::Not(_, _))).unwrap_or(0)
+}
+
+/// Plan of a predicate checked on each entry, of unknown selectivity so never
+/// worth collecting before a tag.
+fn entry_plan(index : &Index, predicate : EntryPredicate) -> Plan {
+    Plan::Predicate(predicate, index.graph.node_count().saturating_sub(index.tags_index.len()))
+}
+
+/// Cost of a union, at most `usize::MAX`.
+fn union_cost(children : &[Plan]) -> usize {
+    children.iter().fold(0usize, |sum, child| sum.saturating_add(child.cost()))
+}
+
+impl Plan {
+    pub fn new(expr : &Expr, index : &Index, config : &Config) -> Self {
+        let (graph, tags_index) = (&index.graph, &index.tags_index);
+        match *expr {
+            Expr::Tag(ref tag) | Expr::Inherited(ref tag) => {
+                let inherit = matches!(*expr, Expr::Inherited(_)) || config.inherit();
+                descendants_plan(graph, config.matching_tags(tag).into_iter()
+                    .filter_map(|synonym| tags_index.get(synonym).cloned()), inherit)
+            },
+            Expr::Glob(ref pattern) => tags_plan(index, config, |tag, _| glob_match(pattern, tag)),
+            Expr::Regex(ref pattern) => match Regex::new(pattern) {
+                Ok(regex) => tags_plan(index, config, |tag, _| regex.is_match(tag)),
+                Err(_) => Plan::Tags(HashSet::new(), 0)
+            },
+            // `key=value` is the exact tag, with its aliases and the tags implying it
+            Expr::Predicate(Predicate::Attribute(ref key, Comparison::Equal, Value::Text(ref value))) =>
+                Plan::new(&Expr::Tag(format!("{}={}", key, value)), index, config),
+            Expr::Predicate(Predicate::Attribute(ref key, Comparison::NotEqual, Value::Text(ref value))) =>
+                tags_plan(index, config, |tag, attribute| match attribute {
+                    Some(attribute) if attribute.key == *key => tag[key.len() + 1..] != **value,
+                    _ => false
+                }),
+            // numbers and dates are compared as such, `priority=3` being `priority=3.0`
+            Expr::Predicate(Predicate::Attribute(ref key, ref comparison, ref value)) =>
+                tags_plan(index, config, |_, attribute| match attribute {
+                    Some(attribute) if attribute.key == *key =>
+                        comparison.test_ordering(attribute.value.compare(value)),
+                    _ => false
+                }),
+            Expr::Predicate(Predicate::Under(ref path)) => {
+                let total = graph.node_count().saturating_sub(tags_index.len());
+                match resolve_path(graph, index.root_index, &index.base_path, path) {
+                    Some(directory) => Plan::Under(Some(directory), total),
+                    None => Plan::Under(None, 0)
+                }
+            },
+            Expr::Predicate(Predicate::Kind(ref kind)) => entry_plan(index, EntryPredicate::Kind(kind.clone())),
+            Expr::Predicate(Predicate::Size(ref comparison, size)) =>
+                entry_plan(index, EntryPredicate::Size(comparison.clone(), size)),
+            Expr::Predicate(Predicate::Mtime(ref comparison, day)) =>
+                entry_plan(index, EntryPredicate::Mtime(comparison.clone(), day)),
+            Expr::Predicate(Predicate::Extension(ref extension)) =>
+                entry_plan(index, EntryPredicate::Extension(extension.clone())),
+            Expr::And(_, _) | Expr::Or(_, _) => {
+                let and = matches!(*expr, Expr::And(_, _));
+                let mut operands = Vec::new();
+                flatten(expr, and, &mut operands);
+                let mut children : Vec<Plan> = operands.into_iter()
+                    .map(|operand| Plan::new(operand, index, config)).collect();
+                children.sort_by_key(|child| child.cost());
+                if and {
+                    let cost = children[0].cost();
+                    Plan::And(children, cost)
+                }
+                else {
+                    let cost = union_cost(&children);
+                    Plan::Or(children, cost)
+                }
+            },
+            Expr::Not(ref operand) => {
+                let operand = Plan::new(operand, index, config);
+                let total = graph.node_count().saturating_sub(tags_index.len());
+                let cost = total.saturating_sub(operand.cost());
+                Plan::Not(Box::new(operand), cost)
+            }
+        }
+    }
+
+    /// Estimated number of entries matching the plan.
+    pub fn cost(&self) -> usize {
+        match *self {
+            Plan::Tags(_, cost) | Plan::Inherited(_, cost) | Plan::And(_, cost) | Plan::Or(_, cost)
+                | Plan::Predicate(_, cost) | Plan::Under(_, cost) | Plan::Not(_, cost) => cost
+        }
+    }
+
+    /// Check if an entry matches the plan, looking only at the tags of the
+    /// entry instead of collecting all the entries of the operands.
+    pub fn contains(&self, graph : &MyGraph, entry : NodeIndex) -> bool {
+        #[cfg(test)]
+        PROBES.with(|probes| probes.set(probes.get() + 1));
+        match *self {
+            Plan::Tags(ref tags, _) => is_tagged(graph, tags, entry),
+            Plan::Inherited(ref tags, _) => {
+                // walk up the directories, like make_path_vec
+                let mut current = Some(entry);
+                while let Some(index) = current {
+                    if is_tagged(graph, tags, index) {
+                        return true;
+                    }
+                    current = get_parent_index(graph, index);
+                }
+                false
+            },
+            Plan::And(ref children, _) => children.iter().all(|child| child.contains(graph, entry)),
+            Plan::Or(ref children, _) => children.iter().any(|child| child.contains(graph, entry)),
+            Plan::Predicate(ref predicate, _) => matches(predicate, graph, entry),
+            Plan::Under(directory, _) => match directory {
+                Some(directory) => is_under(graph, directory, entry),
+                None => false
+            },
+            Plan::Not(ref operand, _) => !operand.contains(graph, entry)
+        }
+    }
+
+    /// Collect the entries matching the plan. An intersection is built from
+    /// its smallest operand, whose entries are probed against the others,
+    /// and stops as soon as it is empty.
+    pub fn execute(&self, graph : &MyGraph) -> HashSet<NodeIndex> {
+        match *self {
+            Plan::Tags(ref tags, _) => {
+                let mut entries = HashSet::new();
+                for tag_index in tags {
+                    entries.extend(tagged_entries(graph, *tag_index));
+                }
+                entries
+            },
+            Plan::Inherited(ref tags, _) => {
+                let mut entries = HashSet::new();
+                for tag_index in tags {
+                    for entry in tagged_entries(graph, *tag_index) {
+                        if entries.insert(entry) && graph.node_weight(entry).unwrap().kind == NodeKind::Directory {
+                            descendants(graph, entry, &mut entries);
+                        }
+                    }
+                }
+                entries
+            },
+            Plan::And(ref children, _) => {
+                let driver = driver(children);
+                let mut entries = children[driver].execute(graph);
+                for (i, child) in children.iter().enumerate() {
+                    if entries.is_empty() {
+                        break;
+                    }
+                    if i != driver {
+                        entries.retain(|entry| child.contains(graph, *entry));
+                    }
+                }
+                entries
+            },
+            Plan::Or(ref children, _) => {
+                let mut entries = HashSet::new();
+                for child in children.iter().rev() {
+                    entries.extend(child.execute(graph));
+                }
+                entries
+            },
+            Plan::Predicate(ref predicate, _) => {
+                let mut entries = all_entries(graph);
+                entries.retain(|entry| matches(predicate, graph, *entry));
+                entries
+            },
+            Plan::Under(directory, _) => {
+                let mut entries = HashSet::new();
+                if let Some(directory) = directory {
+                    descendants(graph, directory, &mut entries);
+                }
+                entries
+            },
+            Plan::Not(ref operand, _) => {
+                let mut entries = all_entries(graph);
+                entries.retain(|entry| !operand.contains(graph, *entry));
+                entries
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph::test_index;
+    use parse::parse;
+
+    /// `root` with the files `a`, `b` and `c`, all tagged `common`, `c` also
+    /// tagged `rare`.
+    fn index() -> Index {
+        test_index(&[("root/a", &["common"]), ("root/b", &["common"]), ("root/c", &["common", "rare"])])
+    }
+
+    fn plan(index : &Index, infix : &str) -> Plan {
+        Plan::new(&parse(infix).unwrap(), index, &Config::default())
+    }
+
+    fn costs(plan : &Plan) -> Vec<usize> {
+        match *plan {
+            Plan::And(ref children, _) | Plan::Or(ref children, _) => children.iter().map(Plan::cost).collect(),
+            _ => panic!("not a chain {:?}", plan)
+        }
+    }
+
+    #[test]
+    fn test_sorted_children() {
+        let index = index();
+        let and = plan(&index, "common AND rare AND unknown");
+        assert_eq!(costs(&and), vec![0, 1, 3]);
+        assert_eq!(and.cost(), 0);
+        let or = plan(&index, "common OR (rare OR NOT rare)");
+        assert_eq!(costs(&or), vec![1, 3, 3]);
+        assert_eq!(or.cost(), 7);
+    }
+
+    #[test]
+    fn test_empty_intersection() {
+        let index = index();
+        let probes = |infix| {
+            PROBES.with(|probes| probes.set(0));
+            let entries = plan(&index, infix).execute(&index.graph);
+            (entries.len(), PROBES.with(Cell::get))
+        };
+        assert_eq!(probes("common AND unknown AND is:file"), (0, 0));
+        assert_eq!(probes("common AND rare AND is:file"), (1, 2));
+        assert_eq!(probes("is:dir AND common"), (0, 3));
+    }
+
+    #[test]
+    fn test_attribute_values() {
+        let index = test_index(&[("root/a", &["client=acme/emea", "path=/c/z"]),
+            ("root/b", &["client=acme", "path=/c"])]);
+        let query = |infix| {
+            let mut names : Vec<String> = plan(&index, infix).execute(&index.graph).into_iter()
+                .map(|entry| index.graph.node_weight(entry).unwrap().name.clone()).collect();
+            names.sort();
+            names
+        };
+        assert_eq!(query("client=acme"), vec!["b"]);
+        assert_eq!(query("client!=acme"), vec!["a"]);
+        assert!(query("client=acme AND client!=acme").is_empty());
+        assert_eq!(query("client=acme/emea"), vec!["a"]);
+        assert_eq!(query("path</c/b"), vec!["b"]);
+        assert_eq!(query("path>/c/b"), vec!["a"]);
+    }
+
+    #[test]
+    fn test_configured_tags() {
+        use std::env::temp_dir;
+        use std::fs::{remove_file, write};
+        let path = format!("{}/tag_engine_planner_{}", temp_dir().display(), std::process::id());
+        write(&path, "bill = rare\ncommon => work\n").unwrap();
+        let config = Config::load(path.clone()).unwrap();
+        remove_file(&path).unwrap();
+        let index = index();
+        let count = |infix| Plan::new(&parse(infix).unwrap(), &index, &config).execute(&index.graph).len();
+        assert_eq!(count("bi*"), 1);
+        assert_eq!(count("re:/^wo/ AND NOT rare"), 2);
+        assert!(plan(&index, "bi*").execute(&index.graph).is_empty());
+    }
+
+    #[test]
+    fn test_driver() {
+        let index = index();
+        let not = || Plan::Not(Box::new(Plan::Tags(HashSet::new(), 0)), 0);
+        assert_eq!(driver(&[not(), not(), Plan::Tags(HashSet::new(), 5)]), 2);
+        assert_eq!(driver(&[not(), not()]), 0);
+        let and = plan(&index, "NOT rare AND common");
+        match and {
+            Plan::And(ref children, _) => assert_eq!(driver(children), 1),
+            _ => panic!("not an intersection {:?}", and)
+        }
+        assert_eq!(and.execute(&index.graph).len(), 2);
+    }
+
+    #[test]
+    fn test_union_cost() {
+        let tags = |cost| Plan::Tags(HashSet::new(), cost);
+        assert_eq!(union_cost(&[tags(1), tags(2)]), 3);
+        assert_eq!(union_cost(&[tags(usize::MAX - 1), tags(2), tags(3)]), usize::MAX);
+        assert_eq!(union_cost(&[]), 0);
+    }
+}
@@ -1,8 +1,13 @@
 use std::collections::{HashMap, HashSet};
+use std::io;
 use std::io::prelude::*;
-use std::sync::{Mutex, Arc};
+use std::sync::{Mutex, RwLock, Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::sync_channel;
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::fs::remove_file;
+use std::thread;
+use std::time::Duration;
 
 extern crate petgraph;
 use petgraph::graph::NodeIndex;
@@ -10,45 +15,193 @@ use petgraph::Direction;
 
 extern crate tag_manager;
 
-use graph::{MyGraph, NodeKind};
-use parse::{Arg, Operator};
-use parse::infix_to_postfix;
+use serde_json;
+
+use graph::{MyGraph, Index, NodeKind, find_node_index, get_tags, update_tags, tagged_entries,
+    tag_descendants, parent_tag};
+use parse::{ParseError, parse, evaluate};
+use config::Config;
 
 const BUFFER_SIZE : usize = 4096;
 const CODE_SIZE : usize = 3;
-const BIND_ADDRESS : &str = "/tmp/tag_engine";
+/// First byte of the legacy requests, like `0x0tag`. A framed request never
+/// starts with it, its length would exceed `MAX_FRAME_SIZE`.
+const LEGACY_PREFIX : u8 = b'0';
+const LENGTH_SIZE : usize = 4;
+const MAX_FRAME_SIZE : usize = 1 << 20;
+const STATUS_OK : u8 = 0;
+const STATUS_ERROR : u8 = 1;
+/// Payload of the first frame of a connection switching to the JSON mode.
+const JSON_HANDSHAKE : &str = "json";
+const TREE_OPTION : &str = "tree";
+const TREE_INDENT : &str = "  ";
+/// A stalled client only holds its worker for this long.
+const CONNECTION_TIMEOUT : Duration = Duration::from_secs(5);
+/// JSON sessions served at once, each by its own thread.
+const MAX_JSON_SESSIONS : usize = 64;
+
+/// Requests are either legacy, a code and its argument sent in a single
+/// write and answered with newline terminated lines, or framed, a 4 bytes
+/// big endian length and the UTF-8 code and argument, answered with a
+/// status byte, a 4 bytes big endian length and the UTF-8 lines.
+///
+/// A connection opened with the `JSON_HANDSHAKE` frame is in JSON mode, the
+/// requests and the responses are frames without status byte holding JSON
+/// objects, as many as the client sends before closing the connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Legacy,
+    Framed,
+    Json
+}
+
+/// Result of a request, an optional message, like the confirmation of a
+/// change, and lines, like paths or tags.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Reply {
+    message : Option<String>,
+    lines : Vec<String>
+}
 
-#[derive(Debug, Clone)]
+/// Reply or error message.
+type Response = Result<Reply, String>;
+
+/// In JSON mode a request is an object whose `request` field is the name of
+/// the variant, like `{"request": "rename_tag", "old_name": "a", "new_name": "b"}`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
 enum RequestKind {
-    Entries(String),
+    Entries { expression : String },
     Tags,
-    RenameTag(String)
+    TagsTree,
+    RenameTag { old_name : String, new_name : String },
+    AddAlias { alias : String, canonical : String },
+    RemoveAlias { alias : String },
+    AddImplication { antecedent : String, consequent : String },
+    RemoveImplication { antecedent : String, consequent : String },
+    AddTags { path : String, tags : Vec<String> },
+    RemoveTags { path : String, tags : Vec<String> }
+}
+
+/// Response of the JSON mode, `status` is `ok` or `error`.
+#[derive(Debug, Serialize)]
+struct JsonResponse<'a> {
+    status : &'a str,
+    error : Option<&'a str>,
+    message : Option<&'a str>,
+    result : &'a [String]
+}
+
+/// First message of a connection.
+#[derive(Debug, PartialEq)]
+enum Message {
+    Request(Mode, Result<RequestKind, String>),
+    Handshake
+}
+
+fn invalid_request() -> String {
+    String::from("Invalid request")
+}
+
+/// Arguments of the legacy requests taking two names separated by a space.
+fn split_pair(request : &str) -> Result<(String, String), String> {
+    let v : Vec<&str> = request.split(' ').collect();
+    if v.len() == 2 {
+        Ok((v[0].to_string(), v[1].to_string()))
+    }
+    else {
+        Err(String::from("Bad request"))
+    }
+}
+
+/// Arguments of the legacy requests taking an absolute path and the tags
+/// separated by spaces, the path can't contain any space.
+fn split_path_tags(request : &str) -> Result<(String, Vec<String>), String> {
+    let mut v = request.split(' ').filter(|word| !word.is_empty());
+    match v.next() {
+        Some(path) => {
+            let tags : Vec<String> = v.map(|tag| tag.to_string()).collect();
+            if tags.is_empty() { Err(String::from("Bad request")) }
+            else { Ok((path.to_string(), tags)) }
+        },
+        None => Err(String::from("Bad request"))
+    }
+}
+
+fn request_kind(request : &str) -> Result<RequestKind, String> {
+    if request.len() < CODE_SIZE || !request.is_char_boundary(CODE_SIZE) {
+        return Err(invalid_request());
+    }
+    let (kind, request) = request.split_at(CODE_SIZE);
+    let request = request.trim().to_string();
+    match kind {
+        "0x0" => Ok(RequestKind::Entries { expression : request }),
+        "0x1" => {
+            if request == TREE_OPTION { Ok(RequestKind::TagsTree) }
+            else { Ok(RequestKind::Tags) }
+        },
+        "0x2" => split_pair(&request).map(|(old_name, new_name)| RequestKind::RenameTag { old_name, new_name }),
+        "0x3" => split_pair(&request).map(|(alias, canonical)| RequestKind::AddAlias { alias, canonical }),
+        "0x4" => Ok(RequestKind::RemoveAlias { alias : request }),
+        "0x5" => split_pair(&request)
+            .map(|(antecedent, consequent)| RequestKind::AddImplication { antecedent, consequent }),
+        "0x6" => split_pair(&request)
+            .map(|(antecedent, consequent)| RequestKind::RemoveImplication { antecedent, consequent }),
+        "0x7" => split_path_tags(&request).map(|(path, tags)| RequestKind::AddTags { path, tags }),
+        "0x8" => split_path_tags(&request).map(|(path, tags)| RequestKind::RemoveTags { path, tags }),
+        _ => Err(invalid_request())
+    }
+}
+
+fn json_request(payload : &[u8]) -> Result<RequestKind, String> {
+    serde_json::from_slice(payload).map_err(|error| format!("Invalid request : {}", error))
+}
+
+fn json_response(response : &Response) -> String {
+    let json = match *response {
+        Ok(ref reply) => JsonResponse { status : "ok", error : None,
+            message : reply.message.as_deref(), result : &reply.lines },
+        Err(ref error) => JsonResponse { status : "error", error : Some(error), message : None, result : &[] }
+    };
+    serde_json::to_string(&json).expect("json_response, serialize")
+}
+
+/// Read the payload of a frame whose length was already read.
+fn read_payload<R : Read>(stream : &mut R, length : [u8; LENGTH_SIZE]) -> io::Result<Vec<u8>> {
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes", length)));
+    }
+    let mut payload = vec![0; length];
+    stream.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+fn read_frame<R : Read>(stream : &mut R) -> io::Result<Vec<u8>> {
+    let mut length = [0; LENGTH_SIZE];
+    stream.read_exact(&mut length)?;
+    read_payload(stream, length)
 }
 
-fn parse_request(stream : &mut UnixStream) -> Option<RequestKind> {
+fn read_request<R : Read>(stream : &mut R) -> io::Result<Message> {
     let mut buffer = [0; BUFFER_SIZE];
-    let size = stream.read(&mut buffer).unwrap();
-    if size >= CODE_SIZE {
-        let mut request = String::new();
-        for i in CODE_SIZE..size {
-            request.push(buffer[i] as char);
-        }
-        let mut kind = String::new();
-        for i in 0..CODE_SIZE {
-            kind.push(buffer[i] as char);
-        }
-        if kind == String::from("0x0") {
-            Some(RequestKind::Entries(request.trim().to_string()))
-        }
-        else if kind == String::from("0x1") {
-            Some(RequestKind::Tags)
-        }
-        else if kind == String::from("0x2") {
-            Some(RequestKind::RenameTag(request.trim().to_string()))
-        }
-        else { None }
+    stream.read_exact(&mut buffer[..1])?;
+    if buffer[0] == LEGACY_PREFIX {
+        let size = 1 + stream.read(&mut buffer[1..])?;
+        let request = String::from_utf8_lossy(&buffer[..size]);
+        return Ok(Message::Request(Mode::Legacy, request_kind(&request)));
+    }
+    let mut length = [0; LENGTH_SIZE];
+    length[0] = buffer[0];
+    stream.read_exact(&mut length[1..])?;
+    let payload = read_payload(stream, length)?;
+    if payload == JSON_HANDSHAKE.as_bytes() {
+        return Ok(Message::Handshake);
+    }
+    match String::from_utf8(payload) {
+        Ok(request) => Ok(Message::Request(Mode::Framed, request_kind(&request))),
+        Err(_) => Ok(Message::Request(Mode::Framed, Err(invalid_request())))
     }
-    else { None }
 }
 
 fn make_path_vec(graph : &MyGraph, entry : NodeIndex, path_vec : &mut Vec<String>) {
@@ -75,140 +228,553 @@ fn make_path(graph : &MyGraph, entry : NodeIndex, base_path : String) -> String
     path
 }
 
-fn entries(graph : &MyGraph, tag_index : NodeIndex, base_path : String) -> Vec<String> {
+fn expression_to_entries(infix_request : String, index : &Index, config : &Config)
+    -> Result<Vec<String>, ParseError> {
+    let expr = parse(&infix_request)?;
     let mut nodes_names = Vec::new();
-    for entry in graph.neighbors(tag_index) {
-        nodes_names.push(make_path(graph, entry, base_path.clone()));
+    for entry in evaluate(&expr, index, config) {
+        nodes_names.push(make_path(&index.graph, entry, index.base_path.clone()));
     }
     nodes_names.sort();
-    nodes_names
-}
-
-fn expression_to_entries(infix_request : String, graph : &MyGraph, tags_index : &HashMap<String, 
-    NodeIndex>, base_path : String) -> Vec<String> {
-    let postfix = infix_to_postfix(infix_request.clone());
-    let mut stack = Vec::new();
-    for arg in postfix {
-        match arg {
-            Arg::Operand(tag) => {
-                if tags_index.contains_key(&tag) {
-                    let tag_index = tags_index.get(&tag).unwrap();
-                    let tags_set : HashSet<NodeIndex> = graph.neighbors(*tag_index).collect();
-                    stack.push(tags_set);
-                }
-                else { stack.push(HashSet::new()); }
-            },
-            Arg::Operator(op) => {
-                if stack.len() >= 2 {
-                    let operand_two = stack.pop().unwrap();
-                    let operand_one = stack.pop().unwrap();
-                    match op {
-                        Operator::AND => stack.push(operand_one.intersection(&operand_two).map(|e| *e).collect()),
-                        Operator::OR => stack.push(operand_one.union(&operand_two).map(|e| *e).collect())
-                    }
-                }
+    Ok(nodes_names)
+}
+
+fn write_response<W : Write>(response : Response, mode : Mode, stream : &mut W) -> io::Result<()> {
+    let mut bytes : Vec<u8> = Vec::new();
+    match mode {
+        Mode::Legacy => {
+            let lines = match response {
+                Ok(reply) => reply.message.into_iter().chain(reply.lines).collect(),
+                Err(message) => vec![message]
+            };
+            for line in lines {
+                bytes.extend_from_slice(line.as_bytes());
+                bytes.push(b'\n');
             }
+        },
+        Mode::Framed => {
+            let (status, payload) = match response {
+                Ok(reply) => {
+                    let lines : Vec<String> = reply.message.into_iter().chain(reply.lines).collect();
+                    (STATUS_OK, lines.join("\n"))
+                },
+                Err(message) => (STATUS_ERROR, message)
+            };
+            bytes.push(status);
+            bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(payload.as_bytes());
+        },
+        Mode::Json => {
+            let payload = json_response(&response);
+            bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(payload.as_bytes());
         }
     }
-    let mut nodes_names = Vec::new();
-    if stack.len() == 1 {
-        for entry in stack.pop().unwrap() {
-            nodes_names.push(make_path(graph, entry, base_path.clone()));
+    stream.write_all(bytes.as_slice())?;
+    stream.flush()
+}
+
+fn request_entries(expression : String, index_thread : &Arc<RwLock<Index>>, config_thread : &Arc<RwLock<Config>>)
+    -> Response {
+    println!("########## Request for Entries {:?} ##########", expression);
+    let index = index_thread.read().unwrap();
+    let config = config_thread.read().unwrap();
+    expression_to_entries(expression, &index, &config)
+        .map(|lines| Reply { message : None, lines })
+        .map_err(|error| format!("Invalid expression : {}", error))
+}
+
+fn request_tags(index_thread : &Arc<RwLock<Index>>) -> Response {
+    println!("########## Request for Tags ##########");
+    let index = index_thread.read().unwrap();
+    let mut entries : Vec<String> = index.tags_index.keys().cloned().collect();
+    entries.sort();
+    Ok(Reply { message : None, lines : entries })
+}
+
+fn tags_tree(graph : &MyGraph, tag_index : NodeIndex, depth : usize, lines : &mut Vec<String>) {
+    let name = &graph.node_weight(tag_index).unwrap().name;
+    let leaf = match parent_tag(name) {
+        Some(parent) => &name[parent.len() + 1..],
+        None => name
+    };
+    lines.push(format!("{}{}", TREE_INDENT.repeat(depth), leaf));
+    let mut children : Vec<NodeIndex> = graph.neighbors_directed(tag_index, Direction::Outgoing)
+        .filter(|index| graph.node_weight(*index).unwrap().kind == NodeKind::Tag).collect();
+    children.sort_by_key(|index| graph.node_weight(*index).unwrap().name.clone());
+    for child in children {
+        tags_tree(graph, child, depth + 1, lines);
+    }
+}
+
+fn request_tags_tree(index_thread : &Arc<RwLock<Index>>) -> Response {
+    println!("########## Request for Tags tree ##########");
+    let index = index_thread.read().unwrap();
+    let mut roots : Vec<(&String, &NodeIndex)> = index.tags_index.iter()
+        .filter(|&(tag, _)| parent_tag(tag).is_none()).collect();
+    roots.sort();
+    let mut lines = Vec::new();
+    for (_, tag_index) in roots {
+        tags_tree(&index.graph, *tag_index, 0, &mut lines);
+    }
+    Ok(Reply { message : None, lines })
+}
+
+/// Node of an absolute path, `None` if it isn't in the graph.
+fn find_entry(index : &Index, base_path : &str, path : &str) -> Option<NodeIndex> {
+    match path.get(base_path.len()..) {
+        Some(local) if path.starts_with(base_path) => find_node_index(index.root_index, &index.graph, local),
+        _ => None
+    }
+}
+
+/// Tags to rename by path, for the entries tagged with `old_name` or one of
+/// its descendant tags. `None` if there is no such tag.
+fn tag_renames(index : &Index, old_name : &str, new_name : &str, base_path : &str)
+    -> Option<HashMap<String, Vec<(String, String)>>> {
+    let tag_index = *index.tags_index.get(old_name)?;
+    let mut tags = HashSet::new();
+    tag_descendants(&index.graph, tag_index, &mut tags);
+    let mut renames : HashMap<String, Vec<(String, String)>> = HashMap::new();
+    for tag in tags {
+        let name = &index.graph.node_weight(tag).unwrap().name;
+        let renamed = format!("{}{}", new_name, &name[old_name.len()..]);
+        for entry in tagged_entries(&index.graph, tag) {
+            renames.entry(make_path(&index.graph, entry, base_path.to_string())).or_default()
+                .push((name.clone(), renamed.clone()));
         }
-        nodes_names.sort();
     }
-    nodes_names
+    Some(renames)
 }
 
-fn write_response(entries : Vec<String>, stream : &mut UnixStream) {
-    let mut response : Vec<u8> = Vec::new();
-    for name in entries {
-        for byte in name.as_bytes() {
-            response.push(*byte);
+/// Rename a tag and its descendant tags, `lang/rust` becoming `code/rust`
+/// when renaming `lang` to `code`.
+fn request_rename_tag(old_name : String, new_name : String, index_thread : &Arc<RwLock<Index>>,
+    base_path : String) -> Response {
+    println!("########## Request for RenameTag {:?} {:?} ##########", old_name, new_name);
+    let renames = match tag_renames(&index_thread.read().unwrap(), &old_name, &new_name, &base_path) {
+        Some(renames) => renames,
+        None => return Err(String::from("No tag with this old name"))
+    };
+    // the attributes are written without blocking the queries
+    for (path, tags) in &renames {
+        for (old_tag, new_tag) in tags {
+            tag_manager::rename_tag(path, old_tag.clone(), new_tag.clone());
+        }
+    }
+    let mut index = index_thread.write().unwrap();
+    let mut entries = Vec::new();
+    for path in renames.into_keys() {
+        // read the tags back, the new name may belong elsewhere in the hierarchy
+        match find_entry(&index, &base_path, &path) {
+            Some(entry) => {
+                let Index { ref mut graph, ref mut tags_index, .. } = *index;
+                if let Err(error) = update_tags(path.clone(), tags_index, graph, entry) {
+                    eprintln!("rename_tag, {}", error);
+                }
+            },
+            None => eprintln!("rename_tag, {} isn't in the graph anymore", path)
         }
-        response.push('\n' as u8);
+        entries.push(path);
     }
-    stream.write(response.as_slice()).unwrap();
-    stream.flush().unwrap();
+    entries.sort();
+    let message = format!("Rename {:?} to {:?} for files :", old_name, new_name);
+    Ok(Reply { message : Some(message), lines : entries })
 }
 
-fn request_entries(request : String, graph_thread : &Arc<Mutex<MyGraph>>, 
-    tags_index_thread : &Arc<Mutex<HashMap<String, NodeIndex>>>, base_path : String, 
-    stream : &mut UnixStream) {
-    println!("########## Request for Entries {:?} ##########", request);
-    let graph = graph_thread.lock().unwrap();
-    let tags_index = tags_index_thread.lock().unwrap();
-    let entries = expression_to_entries(request, &graph, &tags_index, base_path);
-    if entries.is_empty() {
-        stream.write("No files\n".as_bytes()).unwrap();
-        stream.flush().unwrap();
+fn request_add_alias(alias : String, canonical : String, config_thread : &Arc<RwLock<Config>>) -> Response {
+    println!("########## Request for AddAlias {:?} {:?} ##########", alias, canonical);
+    let mut config = config_thread.write().unwrap();
+    match config.add_alias(&alias, &canonical) {
+        Ok(()) => Ok(Reply { message : Some(format!("Alias {:?} to {:?}", alias, config.canonical(&alias))),
+            lines : Vec::new() }),
+        Err(error) => Err(format!("Invalid alias : {}", error))
     }
-    else {
-        write_response(entries, stream);
+}
+
+fn request_remove_alias(alias : String, config_thread : &Arc<RwLock<Config>>) -> Response {
+    println!("########## Request for RemoveAlias {:?} ##########", alias);
+    let mut config = config_thread.write().unwrap();
+    match config.remove_alias(&alias) {
+        Ok(true) => Ok(Reply { message : Some(format!("Remove alias {:?}", alias)), lines : Vec::new() }),
+        Ok(false) => Err(String::from("No alias with this name")),
+        Err(error) => Err(format!("Invalid alias : {}", error))
     }
 }
 
-fn request_tags(tags_index_thread : &Arc<Mutex<HashMap<String, NodeIndex>>>, stream : &mut UnixStream) {
-    println!("########## Request for Tags ##########");
-    let tags_index = tags_index_thread.lock().unwrap();
-    let mut entries : Vec<String> = tags_index.keys().map(|key| key.clone()).collect();
-    entries.sort();
-    write_response(entries, stream);
+fn request_add_implication(antecedent : String, consequent : String, config_thread : &Arc<RwLock<Config>>) -> Response {
+    println!("########## Request for AddImplication {:?} {:?} ##########", antecedent, consequent);
+    let mut config = config_thread.write().unwrap();
+    match config.add_implication(&antecedent, &consequent) {
+        Ok(()) => {
+            let message = format!("{:?} implies {:?}", config.canonical(&antecedent), config.canonical(&consequent));
+            Ok(Reply { message : Some(message), lines : Vec::new() })
+        },
+        Err(error) => Err(format!("Invalid implication : {}", error))
+    }
 }
 
-fn request_rename_tag(request : String, graph_thread : &Arc<Mutex<MyGraph>>, 
-    tags_index_thread : &Arc<Mutex<HashMap<String, NodeIndex>>>, base_path : String, 
-    stream : &mut UnixStream) {
-    println!("########## Request for RenameTag {:?} ##########", request);
-    let v : Vec<&str> = request.split(' ').collect();
-    if v.len() == 2 {
-        let old_name = v[0];
-        let new_name = v[1];
-        let mut graph = graph_thread.lock().unwrap();
-        let mut tags_index = tags_index_thread.lock().unwrap();
-        match tags_index.remove(old_name) {
-            Some(index) => {
-                tags_index.insert(new_name.to_string(), index);
-                graph.node_weight_mut(index).unwrap().name = new_name.to_string();
-                let mut entries = entries(&graph, index, base_path.clone());
-                for e in &entries {
-                    tag_manager::rename_tag(e, old_name.to_string(), new_name.to_string());
+fn request_remove_implication(antecedent : String, consequent : String,
+    config_thread : &Arc<RwLock<Config>>) -> Response {
+    println!("########## Request for RemoveImplication {:?} {:?} ##########", antecedent, consequent);
+    let mut config = config_thread.write().unwrap();
+    match config.remove_implication(&antecedent, &consequent) {
+        Ok(true) => Ok(Reply { message : Some(format!("Remove implication {:?} => {:?}", antecedent, consequent)),
+            lines : Vec::new() }),
+        Ok(false) => Err(String::from("No implication between these tags")),
+        Err(error) => Err(format!("Invalid implication : {}", error))
+    }
+}
+
+/// Add or remove the tags of `path`, then read them back in the graph right
+/// away, without waiting for the event of the watcher.
+fn write_tags(path : String, tags : Vec<String>, add : bool, index_thread : &Arc<RwLock<Index>>,
+    base_path : String) -> Result<Vec<String>, String> {
+    let path = path.trim_end_matches('/').to_string();
+    if tags.iter().any(|tag| tag.is_empty()) {
+        return Err(String::from("Invalid tags : empty tag"));
+    }
+    if find_entry(&index_thread.read().unwrap(), &base_path, &path).is_none() {
+        return Err(String::from("No entry with this path"));
+    }
+    // no lock is held while writing and checking the attributes, update_tags
+    // reads them again under the write lock
+    let tags : HashSet<String> = tags.into_iter().collect();
+    if add { tag_manager::add_tags(&path, &tags); }
+    else { tag_manager::del_tags(&path, &tags); }
+    let written = tag_manager::get_tags(&path).unwrap_or_default();
+    let failed = if add { !tags.is_subset(&written) } else { !tags.is_disjoint(&written) };
+
+    let mut index = index_thread.write().unwrap();
+    let entry = match find_entry(&index, &base_path, &path) {
+        Some(entry) => entry,
+        None => return Err(String::from("No entry with this path"))
+    };
+    let Index { ref mut graph, ref mut tags_index, .. } = *index;
+    update_tags(path.clone(), tags_index, graph, entry)
+        .map_err(|error| format!("Can't update the tags : {}", error))?;
+    if failed {
+        return Err(format!("Can't write the tags of {:?}", path));
+    }
+    let mut tags : Vec<String> = get_tags(graph, entry).into_iter().collect();
+    tags.sort();
+    Ok(tags)
+}
+
+fn request_add_tags(path : String, tags : Vec<String>, index_thread : &Arc<RwLock<Index>>,
+    base_path : String) -> Response {
+    println!("########## Request for AddTags {:?} {:?} ##########", path, tags);
+    let message = format!("Add {:?} to {:?}, tags :", tags, path);
+    write_tags(path, tags, true, index_thread, base_path).map(|lines| Reply { message : Some(message), lines })
+}
+
+fn request_remove_tags(path : String, tags : Vec<String>, index_thread : &Arc<RwLock<Index>>,
+    base_path : String) -> Response {
+    println!("########## Request for RemoveTags {:?} {:?} ##########", path, tags);
+    let message = format!("Remove {:?} from {:?}, tags :", tags, path);
+    write_tags(path, tags, false, index_thread, base_path).map(|lines| Reply { message : Some(message), lines })
+}
+
+fn handle_request(kind : RequestKind, index_thread : &Arc<RwLock<Index>>,
+    config_thread : &Arc<RwLock<Config>>, base_path : String) -> Response {
+    match kind {
+        RequestKind::Entries { expression } => request_entries(expression, index_thread, config_thread),
+        RequestKind::Tags => request_tags(index_thread),
+        RequestKind::TagsTree => request_tags_tree(index_thread),
+        RequestKind::RenameTag { old_name, new_name } =>
+            request_rename_tag(old_name, new_name, index_thread, base_path),
+        RequestKind::AddAlias { alias, canonical } => request_add_alias(alias, canonical, config_thread),
+        RequestKind::RemoveAlias { alias } => request_remove_alias(alias, config_thread),
+        RequestKind::AddImplication { antecedent, consequent } =>
+            request_add_implication(antecedent, consequent, config_thread),
+        RequestKind::RemoveImplication { antecedent, consequent } =>
+            request_remove_implication(antecedent, consequent, config_thread),
+        RequestKind::AddTags { path, tags } => request_add_tags(path, tags, index_thread, base_path),
+        RequestKind::RemoveTags { path, tags } => request_remove_tags(path, tags, index_thread, base_path)
+    }
+}
+
+fn answer(kind : Result<RequestKind, String>, mode : Mode, index_thread : &Arc<RwLock<Index>>,
+    config_thread : &Arc<RwLock<Config>>, base_path : String) -> Response {
+    let is_entries = matches!(kind, Ok(RequestKind::Entries { .. }));
+    match kind.and_then(|kind| handle_request(kind, index_thread, config_thread, base_path)) {
+        // the legacy clients can't tell an empty response from a closed connection
+        Ok(ref reply) if reply.lines.is_empty() && is_entries && mode == Mode::Legacy =>
+            Ok(Reply { message : Some(String::from("No files")), lines : Vec::new() }),
+        response => response
+    }
+}
+
+/// Answer the JSON requests of a connection until the client closes it.
+fn json_session<S : Read + Write>(stream : &mut S, index_thread : &Arc<RwLock<Index>>,
+    config_thread : &Arc<RwLock<Config>>, base_path : String) -> io::Result<()> {
+    // a server without JSON mode answers the handshake with a framed error
+    write_response(Ok(Reply::default()), Mode::Json, stream)?;
+    loop {
+        let payload = match read_frame(stream) {
+            Ok(payload) => payload,
+            Err(ref error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(error) => return Err(error)
+        };
+        let response = answer(json_request(&payload), Mode::Json, index_thread, config_thread, base_path.clone());
+        write_response(response, Mode::Json, stream)?;
+    }
+}
+
+fn handle_connection(mut stream : UnixStream, index_thread : &Arc<RwLock<Index>>,
+    config_thread : &Arc<RwLock<Config>>, sessions : &Arc<AtomicUsize>, base_path : String) {
+    let message = match read_request(&mut stream) {
+        Ok(message) => message,
+        Err(error) => {
+            eprintln!("read_request, {}", error);
+            return;
+        }
+    };
+    match message {
+        Message::Request(mode, kind) => {
+            let response = answer(kind, mode, index_thread, config_thread, base_path);
+            if let Err(error) = write_response(response, mode, &mut stream) {
+                eprintln!("write_response, {}", error);
+            }
+        },
+        Message::Handshake => {
+            // a session lasts as long as the client wants, it gets its own
+            // thread instead of holding a worker
+            if sessions.fetch_add(1, Ordering::SeqCst) >= MAX_JSON_SESSIONS {
+                sessions.fetch_sub(1, Ordering::SeqCst);
+                if let Err(error) = write_response(Err(String::from("Too many JSON sessions")), Mode::Json,
+                    &mut stream) {
+                    eprintln!("write_response, {}", error);
                 }
-                entries.insert(0, format!("Rename {:?} to {:?} for files :", old_name, new_name));
-                write_response(entries, stream);
-            },
-            None => {
-                write_response(vec![String::from("No tag with this old name")], stream);
+                return;
             }
+            let (index_thread, config_thread) = (Arc::clone(index_thread), Arc::clone(config_thread));
+            let sessions = Arc::clone(sessions);
+            thread::spawn(move || {
+                if let Err(error) = json_session(&mut stream, &index_thread, &config_thread, base_path) {
+                    eprintln!("json_session, {}", error);
+                }
+                sessions.fetch_sub(1, Ordering::SeqCst);
+            });
         }
     }
-    else {
-        write_response(vec![String::from("Bad request")], stream);
-    }
 }
 
-pub fn server(base_path : String, graph : &Arc<Mutex<MyGraph>>, tags_index : &Arc<Mutex<HashMap<String, NodeIndex>>>) {
-    match remove_file(BIND_ADDRESS) {
-        _ => ()
+/// Accept the connections on the Unix socket `socket_path`, replacing any
+/// file there, and hand them to `workers` threads. At most
+/// `workers` connections wait for a free worker, the next ones wait to be
+/// accepted. A connection in JSON mode is handed to its own thread, up to
+/// `MAX_JSON_SESSIONS`, until the client closes it or stays idle for
+/// `CONNECTION_TIMEOUT`.
+pub fn server(socket_path : &str, base_path : String, index : &Arc<RwLock<Index>>,
+    config : &Arc<RwLock<Config>>, workers : usize) {
+    let _ = remove_file(socket_path);
+    let listener = UnixListener::bind(socket_path).unwrap();
+    let (sender, receiver) = sync_channel::<UnixStream>(workers);
+    let receiver = Arc::new(Mutex::new(receiver));
+    let sessions = Arc::new(AtomicUsize::new(0));
+
+    for _ in 0..workers {
+        let receiver = Arc::clone(&receiver);
+        let sessions = Arc::clone(&sessions);
+        let index_thread = Arc::clone(index);
+        let config_thread = Arc::clone(config);
+        let base_path = base_path.clone();
+        thread::spawn(move || loop {
+            // the lock is released before handling the connection
+            let stream = receiver.lock().unwrap().recv();
+            match stream {
+                Ok(stream) =>
+                    handle_connection(stream, &index_thread, &config_thread, &sessions, base_path.clone()),
+                Err(_) => break
+            }
+        });
     }
-    let listener = UnixListener::bind(BIND_ADDRESS).unwrap();
-    let graph_thread = Arc::clone(graph);
-    let tags_index_thread = Arc::clone(tags_index);
 
     for stream in listener.incoming() {
-        let mut stream = stream.unwrap();
-        match parse_request(&mut stream) {
-            Some(kind) => match kind {
-                RequestKind::Entries(request) => request_entries(request, &graph_thread, 
-                    &tags_index_thread, base_path.clone(), &mut stream),
-                RequestKind::Tags => request_tags(&tags_index_thread, &mut stream),
-                RequestKind::RenameTag(request) => request_rename_tag(request, &graph_thread, 
-                    &tags_index_thread, base_path.clone(), &mut stream)
+        match stream {
+            Ok(stream) => {
+                let timeouts = stream.set_read_timeout(Some(CONNECTION_TIMEOUT))
+                    .and_then(|_| stream.set_write_timeout(Some(CONNECTION_TIMEOUT)));
+                match timeouts {
+                    Ok(()) => sender.send(stream).expect("server, workers stopped"),
+                    Err(error) => eprintln!("server, {}", error)
+                }
             },
-            None => {
-                stream.write("Invalid request\n".as_bytes()).unwrap();
-                stream.flush().unwrap();
+            Err(error) => eprintln!("server, {}", error)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph::test_index;
+
+    fn frame(payload : &str) -> Vec<u8> {
+        let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(payload.as_bytes());
+        bytes
+    }
+
+    /// `/data/root` with the files `a`, tagged `lang/rust` and `todo`, and `b`,
+    /// tagged `lang`.
+    fn index() -> Index {
+        test_index(&[("root/a", &["lang/rust", "todo"]), ("root/b", &["lang"])])
+    }
+
+    fn reply(message : Option<&str>, lines : &[&str]) -> Response {
+        Ok(Reply { message : message.map(String::from), lines : lines.iter().map(|line| line.to_string()).collect() })
+    }
+
+    #[test]
+    fn test_read_request() {
+        let read = |bytes : &[u8]| read_request(&mut &bytes[..]).ok();
+        let request = |mode, kind| Some(Message::Request(mode, kind));
+        assert_eq!(read(b"0x0 photo AND caf\xc3\xa9 "),
+            request(Mode::Legacy, Ok(RequestKind::Entries { expression : String::from("photo AND café") })));
+        assert_eq!(read(b"0x1tree"), request(Mode::Legacy, Ok(RequestKind::TagsTree)));
+        assert_eq!(read(b"0x9"), request(Mode::Legacy, Err(String::from("Invalid request"))));
+        assert_eq!(read(b"0x3photo"), request(Mode::Legacy, Err(String::from("Bad request"))));
+        let long = format!("0x0{}", "tag OR ".repeat(1000) + "été");
+        assert_eq!(read(&frame(&long)),
+            request(Mode::Framed, Ok(RequestKind::Entries { expression : long[CODE_SIZE..].to_string() })));
+        assert_eq!(read(&frame("0x2été summer")), request(Mode::Framed,
+            Ok(RequestKind::RenameTag { old_name : String::from("été"), new_name : String::from("summer") })));
+        assert_eq!(read(&frame("0é")), request(Mode::Framed, Err(String::from("Invalid request"))));
+        assert_eq!(read(b"0x7/data/root/a.pdf  todo lang/rust\n"), request(Mode::Legacy, Ok(RequestKind::AddTags {
+            path : String::from("/data/root/a.pdf"), tags : vec![String::from("todo"), String::from("lang/rust")] })));
+        assert_eq!(read(b"0x8/data/root/a.pdf"), request(Mode::Legacy, Err(String::from("Bad request"))));
+        assert_eq!(read(&frame("json")), Some(Message::Handshake));
+        assert_eq!(read(&frame("0x0photo")[..7]), None);
+        assert_eq!(read(&[0, 0x10, 0, 1]), None);
+    }
+
+    #[test]
+    fn test_json_request() {
+        assert_eq!(json_request(r#"{"request": "entries", "expression": "photo AND été"}"#.as_bytes()),
+            Ok(RequestKind::Entries { expression : String::from("photo AND été") }));
+        assert_eq!(json_request(br#"{"request": "tags_tree"}"#), Ok(RequestKind::TagsTree));
+        assert_eq!(json_request(br#"{"request": "remove_implication", "antecedent": "a", "consequent": "b"}"#),
+            Ok(RequestKind::RemoveImplication { antecedent : String::from("a"), consequent : String::from("b") }));
+        assert_eq!(json_request(br#"{"request": "remove_tags", "path": "/data/my root", "tags": ["a", "b"]}"#),
+            Ok(RequestKind::RemoveTags { path : String::from("/data/my root"),
+                tags : vec![String::from("a"), String::from("b")] }));
+        assert!(json_request(br#"{"request": "rename_tag", "old_name": "a"}"#).is_err());
+        assert!(json_request(br#"{"request": "delete"}"#).is_err());
+        assert!(json_request(b"0x1").is_err());
+    }
+
+    #[test]
+    fn test_tag_renames() {
+        let index = index();
+        assert_eq!(find_entry(&index, "/data/", "/data/root/b"), index.graph.node_weight(index.root_index)
+            .unwrap().children.get("b").cloned());
+        assert_eq!(find_entry(&index, "/data/", "/elsewhere/root/b"), None);
+        assert!(tag_renames(&index, "code", "lang", "/data/").is_none());
+        let renames = tag_renames(&index, "lang", "code", "/data/").unwrap();
+        let mut renames : Vec<(String, Vec<(String, String)>)> = renames.into_iter().collect();
+        renames.sort();
+        let rename = |old : &str, new : &str| (old.to_string(), new.to_string());
+        assert_eq!(renames, vec![
+            (String::from("/data/root/a"), vec![rename("lang/rust", "code/rust")]),
+            (String::from("/data/root/b"), vec![rename("lang", "code")])]);
+    }
+
+    #[test]
+    fn test_write_tags() {
+        let index = Arc::new(RwLock::new(index()));
+        let write = |path : &str, tags : &[&str], add| write_tags(path.to_string(),
+            tags.iter().map(|tag| tag.to_string()).collect(), add, &index, String::from("/data/"));
+        let missing = Err(String::from("No entry with this path"));
+        assert_eq!(write("/data/root/missing", &["todo"], true), missing);
+        assert_eq!(write("/elsewhere/root/a", &["todo"], false), missing);
+        assert_eq!(write("/data/root/a/", &["todo", ""], true), Err(String::from("Invalid tags : empty tag")));
+        assert_eq!(index.read().unwrap().tags_index.len(), 3);
+    }
+
+    /// Serve `index()` with `workers` threads on a socket named after `name`.
+    fn start_server(name : &str, workers : usize) -> String {
+        let socket = format!("{}/tag_engine_{}_{}", std::env::temp_dir().display(), name, std::process::id());
+        let socket_path = socket.clone();
+        let index = Arc::new(RwLock::new(index()));
+        let config = Arc::new(RwLock::new(Config::default()));
+        thread::spawn(move || server(&socket_path, String::from("/data/"), &index, &config, workers));
+        socket
+    }
+
+    fn connect(socket : &str) -> UnixStream {
+        for _ in 0..100 {
+            if let Ok(stream) = UnixStream::connect(socket) {
+                return stream;
             }
+            thread::sleep(Duration::from_millis(10));
         }
+        panic!("no server on {}", socket);
+    }
+
+    /// Send the framed request for the tags, which must be answered before
+    /// the stalled clients time out.
+    fn assert_tags_served(socket : &str) {
+        let start = std::time::Instant::now();
+        let mut client = connect(socket);
+        client.write_all(&frame("0x1")).unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        assert!(start.elapsed() < CONNECTION_TIMEOUT / 2);
+        let mut framed = vec![STATUS_OK];
+        framed.extend(frame("lang\nlang/rust\ntodo"));
+        assert_eq!(response, framed);
+    }
+
+    #[test]
+    fn test_stalled_clients() {
+        let socket = start_server("stalled", 3);
+        // both hold a worker until CONNECTION_TIMEOUT, one silent, the other
+        // in the middle of a frame
+        let _silent = connect(&socket);
+        let mut partial = connect(&socket);
+        partial.write_all(&frame("0x1")[..LENGTH_SIZE + 1]).unwrap();
+        assert_tags_served(&socket);
+        remove_file(&socket).unwrap();
+    }
+
+    #[test]
+    fn test_json_sessions() {
+        let socket = start_server("json", 1);
+        let mut sessions : Vec<UnixStream> = (0..2).map(|_| connect(&socket)).collect();
+        for session in &mut sessions {
+            session.write_all(&frame(JSON_HANDSHAKE)).unwrap();
+            assert!(read_frame(session).is_ok());
+        }
+        assert_tags_served(&socket);
+        sessions[0].write_all(&frame(r#"{"request": "tags"}"#)).unwrap();
+        assert_eq!(String::from_utf8(read_frame(&mut sessions[0]).unwrap()).unwrap(),
+            r#"{"status":"ok","error":null,"message":null,"result":["lang","lang/rust","todo"]}"#);
+        remove_file(&socket).unwrap();
+    }
+
+    #[test]
+    fn test_write_response() {
+        let write = |response, mode| {
+            let mut bytes = Vec::new();
+            write_response(response, mode, &mut bytes).unwrap();
+            bytes
+        };
+        let lines = ["/data/a", "/data/é"];
+        assert_eq!(write(reply(None, &lines), Mode::Legacy), b"/data/a\n/data/\xc3\xa9\n".to_vec());
+        assert_eq!(write(reply(Some("Rename"), &lines[..1]), Mode::Legacy), b"Rename\n/data/a\n".to_vec());
+        assert_eq!(write(Err(String::from("Bad request")), Mode::Legacy), b"Bad request\n".to_vec());
+        let mut framed = vec![STATUS_OK];
+        framed.extend(frame("/data/a\n/data/é"));
+        assert_eq!(write(reply(None, &lines), Mode::Framed), framed);
+        assert_eq!(write(reply(None, &[]), Mode::Framed), vec![STATUS_OK, 0, 0, 0, 0]);
+        let mut framed = vec![STATUS_ERROR];
+        framed.extend(frame("No files"));
+        assert_eq!(write(Err(String::from("No files")), Mode::Framed), framed);
+        assert_eq!(write(reply(Some("Rename"), &lines), Mode::Json),
+            frame(r#"{"status":"ok","error":null,"message":"Rename","result":["/data/a","/data/é"]}"#));
+        assert_eq!(write(Err(String::from("No tag with this old name")), Mode::Json),
+            frame(r#"{"status":"error","error":"No tag with this old name","message":null,"result":[]}"#));
     }
 }
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::fs::{File, rename};
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use petgraph::graph::NodeIndex;
+
+use graph::{MyGraph, Node, NodeKind, FileMeta, Nil, add_child};
+
+const MAGIC : &[u8; 4] = b"TGES";
+/// Incremented on each change of the format, older snapshots are rejected.
+const VERSION : u32 = 2;
+/// Events debounced by the watcher may not be applied to a snapshot yet.
+const CLOCK_MARGIN : i64 = 5;
+
+/// Graph and tags index read back from a snapshot, `written_at` in seconds
+/// since the Unix epoch.
+pub struct Snapshot {
+    pub graph : MyGraph,
+    pub tags_index : HashMap<String, NodeIndex>,
+    pub root_index : NodeIndex,
+    pub written_at : i64
+}
+
+/// Default path of the snapshot of a root, `/data/.finance.tag_engine.snapshot`
+/// for `/data/finance`.
+pub fn snapshot_path(base_path : &str, root_name : &str) -> String {
+    format!("{}.{}.tag_engine.snapshot", base_path, root_name)
+}
+
+fn invalid_data(message : &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs() as i64).unwrap_or(0)
+}
+
+fn write_u64<W : Write>(writer : &mut W, value : u64) -> io::Result<()> {
+    writer.write_all(&value.to_be_bytes())
+}
+
+fn write_string<W : Write>(writer : &mut W, value : &str) -> io::Result<()> {
+    write_u64(writer, value.len() as u64)?;
+    writer.write_all(value.as_bytes())
+}
+
+fn read_u8<R : Read>(reader : &mut R) -> io::Result<u8> {
+    let mut buffer = [0; 1];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer[0])
+}
+
+fn read_u64<R : Read>(reader : &mut R) -> io::Result<u64> {
+    let mut buffer = [0; 8];
+    reader.read_exact(&mut buffer)?;
+    Ok(u64::from_be_bytes(buffer))
+}
+
+fn read_string<R : Read>(reader : &mut R) -> io::Result<String> {
+    let length = read_u64(reader)?;
+    let mut buffer = Vec::new();
+    reader.take(length).read_to_end(&mut buffer)?;
+    if buffer.len() as u64 != length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "snapshot truncated"));
+    }
+    String::from_utf8(buffer).map_err(|_| invalid_data("invalid UTF-8 name"))
+}
+
+/// Nodes are written in the order of their indexes, edges and the root
+/// refer to these positions.
+fn write_graph<W : Write>(writer : &mut W, path_root : &str, graph : &MyGraph, root_index : NodeIndex,
+    written_at : i64) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_be_bytes())?;
+    writer.write_all(&written_at.to_be_bytes())?;
+    write_string(writer, path_root)?;
+    let mut positions = HashMap::new();
+    write_u64(writer, graph.node_count() as u64)?;
+    for (position, index) in graph.node_indices().enumerate() {
+        positions.insert(index, position as u64);
+        let node = graph.node_weight(index).unwrap();
+        let kind = match node.kind {
+            NodeKind::Tag => 0,
+            NodeKind::File => 1,
+            NodeKind::Directory => 2
+        };
+        writer.write_all(&[kind])?;
+        write_string(writer, &node.name)?;
+        match node.meta {
+            Some(ref meta) => {
+                writer.write_all(&[1])?;
+                write_u64(writer, meta.size)?;
+                writer.write_all(&meta.mtime.to_be_bytes())?;
+            },
+            None => writer.write_all(&[0])?
+        }
+        match node.inode {
+            Some(inode) => {
+                writer.write_all(&[1])?;
+                write_u64(writer, inode)?;
+            },
+            None => writer.write_all(&[0])?
+        }
+    }
+    write_u64(writer, positions[&root_index])?;
+    write_u64(writer, graph.edge_count() as u64)?;
+    for edge in graph.edge_indices() {
+        let (source, target) = graph.edge_endpoints(edge).unwrap();
+        write_u64(writer, positions[&source])?;
+        write_u64(writer, positions[&target])?;
+    }
+    Ok(())
+}
+
+fn read_graph<R : Read>(reader : &mut R, path_root : &str) -> io::Result<Snapshot> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a snapshot"));
+    }
+    let mut buffer = [0; 4];
+    reader.read_exact(&mut buffer)?;
+    let version = u32::from_be_bytes(buffer);
+    if version != VERSION {
+        return Err(invalid_data(&format!("snapshot version {}, expected {}", version, VERSION)));
+    }
+    let written_at = read_u64(reader)? as i64;
+    if read_string(reader)? != path_root {
+        return Err(invalid_data("snapshot of another root"));
+    }
+    let mut graph = MyGraph::new();
+    let mut tags_index = HashMap::new();
+    let mut indexes = Vec::new();
+    for _ in 0..read_u64(reader)? {
+        let kind = match read_u8(reader)? {
+            0 => NodeKind::Tag,
+            1 => NodeKind::File,
+            2 => NodeKind::Directory,
+            _ => return Err(invalid_data("invalid node kind"))
+        };
+        let name = read_string(reader)?;
+        let meta = match read_u8(reader)? {
+            0 => None,
+            1 => {
+                let size = read_u64(reader)?;
+                let mtime = read_u64(reader)? as i64;
+                Some(FileMeta { size, mtime, extension : None })
+            },
+            _ => return Err(invalid_data("invalid node metadata"))
+        };
+        let inode = match read_u8(reader)? {
+            0 => None,
+            1 => Some(read_u64(reader)?),
+            _ => return Err(invalid_data("invalid node inode"))
+        };
+        let mut node = Node { name : String::new(), kind, meta, attribute : None, inode,
+            children : HashMap::new() };
+        // recompute the extension and the attribute
+        node.set_name(name);
+        let index = graph.add_node(node);
+        let node = graph.node_weight(index).unwrap();
+        if node.kind == NodeKind::Tag {
+            tags_index.insert(node.name.clone(), index);
+        }
+        indexes.push(index);
+    }
+    let node = |position : u64| -> io::Result<NodeIndex> {
+        indexes.get(position as usize).cloned().ok_or_else(|| invalid_data("invalid node position"))
+    };
+    let root_index = node(read_u64(reader)?)?;
+    for _ in 0..read_u64(reader)? {
+        let source = node(read_u64(reader)?)?;
+        let target = node(read_u64(reader)?)?;
+        if graph.node_weight(source).unwrap().kind == NodeKind::Directory {
+            add_child(&mut graph, source, target);
+        }
+        else { graph.add_edge(source, target, Nil); }
+    }
+    Ok(Snapshot { graph, tags_index, root_index, written_at })
+}
+
+/// Write the graph of `path_root` to `path`, through a temporary file so
+/// that an interrupted write leaves the previous snapshot intact.
+pub fn save(path : &str, path_root : &str, graph : &MyGraph, root_index : NodeIndex) -> io::Result<()> {
+    let temporary = format!("{}.tmp", path);
+    {
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        write_graph(&mut writer, path_root, graph, root_index, now())?;
+        writer.flush()?;
+    }
+    rename(temporary, path)
+}
+
+/// Read the snapshot of `path_root`, failing if it was written by another
+/// version or for another root.
+pub fn load(path : &str, path_root : &str) -> io::Result<Snapshot> {
+    let mut reader = BufReader::new(File::open(path)?);
+    read_graph(&mut reader, path_root)
+}
+
+impl Snapshot {
+    /// Time from which the entries must be read again.
+    pub fn changed_since(&self) -> i64 {
+        self.written_at - CLOCK_MARGIN
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let mut graph = MyGraph::new();
+        let node = |name : &str, kind, meta| {
+            let mut node = Node { name : String::new(), kind, meta, attribute : None, inode : Some(7),
+                children : HashMap::new() };
+            node.set_name(name.to_string());
+            node
+        };
+        let removed = graph.add_node(node("removed", NodeKind::Tag, None));
+        let root = graph.add_node(node("root", NodeKind::Directory, None));
+        let meta = FileMeta { size : 42, mtime : -3600, extension : None };
+        let file = graph.add_node(node("a.pdf", NodeKind::File, Some(meta)));
+        let tag = graph.add_node(node("priority=1", NodeKind::Tag, None));
+        add_child(&mut graph, root, file);
+        graph.add_edge(tag, file, Nil);
+        graph.remove_node(removed);
+
+        let mut buffer = Vec::new();
+        write_graph(&mut buffer, "/data/root", &graph, root, 1234).unwrap();
+        assert!(read_graph(&mut buffer.as_slice(), "/data/other").is_err());
+        assert!(read_graph(&mut &buffer[..buffer.len() - 1], "/data/root").is_err());
+        let snapshot = read_graph(&mut buffer.as_slice(), "/data/root").unwrap();
+        let graph = snapshot.graph;
+        assert_eq!(snapshot.written_at, 1234);
+        assert_eq!(graph.node_count(), 3);
+        assert_eq!(graph.node_weight(snapshot.root_index).unwrap().name, "root");
+        let tag = snapshot.tags_index["priority=1"];
+        assert!(graph.node_weight(tag).unwrap().attribute.is_some());
+        let file = graph.node_weight(snapshot.root_index).unwrap().children["a.pdf"];
+        let meta = graph.node_weight(file).unwrap().meta.clone().unwrap();
+        assert_eq!((meta.size, meta.mtime, meta.extension), (42, -3600, Some(String::from("pdf"))));
+        assert_eq!(graph.node_weight(file).unwrap().inode, Some(7));
+        assert!(graph.find_edge(tag, file).is_some());
+
+        buffer[4] = 0xff;
+        assert!(read_graph(&mut buffer.as_slice(), "/data/root").is_err());
+    }
+}